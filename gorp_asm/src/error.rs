@@ -0,0 +1,83 @@
+use crate::parser::ParseError;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AsmErrorKind {
+    /// The parser found something other than what it expected.
+    Syntax { expected: String },
+    ValueOutOfRange { max: u64 },
+    OffsetOutOfRange,
+    InvalidMode,
+    UndefinedLabel,
+    DuplicateLabel,
+    JumpOutOfRange,
+    InvalidLabelUse,
+}
+
+/// An assembly error pointing at the offending token in the source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AsmError {
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column of the start of `token`.
+    pub column: usize,
+    pub token: String,
+    pub kind: AsmErrorKind,
+    /// The full source line, used to print a snippet.
+    pub snippet: String,
+}
+
+impl AsmError {
+    /// Builds an error for the token at the start of `rest`, which must be a suffix of `source_line`.
+    pub fn new(line: usize, source_line: &str, rest: &str, kind: AsmErrorKind) -> Self {
+        let offset = source_line.len().saturating_sub(rest.len());
+        let token = rest.split_whitespace().next().unwrap_or("").to_string();
+
+        Self {
+            line,
+            column: source_line[..offset].chars().count() + 1,
+            token,
+            kind,
+            snippet: source_line.to_string(),
+        }
+    }
+
+    pub fn from_parse_error(line: usize, source_line: &str, error: ParseError<'_>) -> Self {
+        Self::new(line, source_line, error.rest, AsmErrorKind::Syntax { expected: error.expected })
+    }
+
+    pub fn message(&self) -> String {
+        let found = if self.token.is_empty() {
+            String::from("end of line")
+        } else {
+            format!("`{}`", self.token)
+        };
+
+        match &self.kind {
+            AsmErrorKind::Syntax { expected } => format!("expected {}, found {}", expected, found),
+            AsmErrorKind::ValueOutOfRange { max } => format!("expected a value from 0 to {}, found {}", max, found),
+            AsmErrorKind::OffsetOutOfRange => format!("expected an offset from -32 to 31, found {}", found),
+            AsmErrorKind::InvalidMode => format!("expected a mode of `i`, `r`, `o` or `s`, found {}", found),
+            AsmErrorKind::UndefinedLabel => format!("undefined label {}", found),
+            AsmErrorKind::DuplicateLabel => format!("duplicate label {}", found),
+            AsmErrorKind::JumpOutOfRange => format!("label {} is out of range for this jump", found),
+            AsmErrorKind::InvalidLabelUse => format!("label {} can only be used as a jump target", found),
+        }
+    }
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep tabs in the padding so the caret lines up with the snippet
+        let padding: String = self.snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "line {}, column {}: {}", self.line, self.column, self.message())?;
+        writeln!(f, "  | {}", self.snippet)?;
+        write!(f, "  | {}^", padding)
+    }
+}
+
+impl std::error::Error for AsmError {}
@@ -1,42 +1,84 @@
+mod error;
+pub mod object;
 mod parser;
+mod program;
 
-use crate::parser::{Parser, literal, one_of, predicate, pair, one_or_more, optional, map};
+use crate::parser::{
+    Parser, ParseError, ParseResult, literal, literal_ignore_case, one_of, predicate, pair, left,
+    zero_or_more, one_or_more, optional, map, describe, whitespace, end_of_line,
+};
+
+pub use crate::error::{AsmError, AsmErrorKind};
+pub use crate::program::{assemble, assemble_object, assemble_object_at, parse_program};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Value(String, String),
+    Label(String),
+}
 
 pub fn opcode<'a>() -> impl Parser<'a, String> {
     move |input| {
         let parsers = vec![
-            literal("hlt"),
-            literal("ldr"),
-            literal("str"),
-            literal("set"),
-            literal("cpy"),
-            literal("jpt"),
-            literal("jpf"),
-            literal("add"),
-            literal("sub"),
-            literal("mul"),
-            literal("div"),
-            literal("mod"),
-            literal("eql"),
-            literal("neq"),
-            literal("let"),
-            literal("leq"),
-            literal("grt"),
-            literal("geq"),
-            literal("sti"),
-            literal("sto"),
+            literal_ignore_case("hlt"),
+            literal_ignore_case("ldr"),
+            literal_ignore_case("str"),
+            literal_ignore_case("setw"),
+            literal_ignore_case("set"),
+            literal_ignore_case("cpy"),
+            literal_ignore_case("psh"),
+            literal_ignore_case("pop"),
+            literal_ignore_case("jpt"),
+            literal_ignore_case("jpf"),
+            literal_ignore_case("cal"),
+            literal_ignore_case("ret"),
+            literal_ignore_case("jmp"),
+            literal_ignore_case("jpa"),
+            literal_ignore_case("jpi"),
+            literal_ignore_case("jfs"),
+            literal_ignore_case("jfc"),
+            literal_ignore_case("add"),
+            literal_ignore_case("sub"),
+            literal_ignore_case("mul"),
+            literal_ignore_case("div"),
+            literal_ignore_case("mod"),
+            literal_ignore_case("sdv"),
+            literal_ignore_case("smd"),
+            literal_ignore_case("eql"),
+            literal_ignore_case("neq"),
+            literal_ignore_case("let"),
+            literal_ignore_case("leq"),
+            literal_ignore_case("grt"),
+            literal_ignore_case("geq"),
+            literal_ignore_case("slt"),
+            literal_ignore_case("sle"),
+            literal_ignore_case("sgt"),
+            literal_ignore_case("sge"),
+            literal_ignore_case("and"),
+            literal_ignore_case("orr"),
+            literal_ignore_case("xor"),
+            literal_ignore_case("not"),
+            literal_ignore_case("shl"),
+            literal_ignore_case("shr"),
+            literal_ignore_case("sar"),
+            literal_ignore_case("sti"),
+            literal_ignore_case("sto"),
+            literal_ignore_case("int"),
+            literal_ignore_case("iret"),
+            literal_ignore_case("eni"),
+            literal_ignore_case("dsi"),
+            literal_ignore_case("wfi"),
         ];
 
-        one_of(parsers).parse(input)
+        describe(one_of(parsers), "an opcode").parse(input)
     }
 }
 
 pub fn value<'a>() -> impl Parser<'a, (String, String)> {
     move |input| {
-        let number = |input| one_or_more(input, char::is_numeric);
         let postfix = |input| predicate(input, char::is_alphabetic);
 
-        map(pair(number, optional(postfix)), |(number, mode)| {
+        map(pair(number(), optional(postfix)), |(number, mode)| {
             let mode = match mode {
                 Some(letter) => letter,
                 None => String::from("i"),
@@ -45,72 +87,293 @@ pub fn value<'a>() -> impl Parser<'a, (String, String)> {
             (number, mode)
         })
         .parse(input)
+        .map_err(|error| ParseError::new(error.rest, "a value"))
+    }
+}
+
+pub fn label<'a>() -> impl Parser<'a, String> {
+    move |input| {
+        let first = |input| predicate(input, |ch| ch.is_alphabetic() || ch == '_');
+        let rest = |input| zero_or_more(input, |ch| ch.is_alphanumeric() || ch == '_');
+
+        describe(map(pair(first, rest), |(first, rest)| first + &rest), "a label").parse(input)
+    }
+}
+
+pub fn label_definition<'a>() -> impl Parser<'a, String> {
+    move |input| {
+        left(label(), literal(":")).parse(input)
+    }
+}
+
+pub fn operand<'a>() -> impl Parser<'a, Operand> {
+    move |input| {
+        map(value(), |(number, mode)| Operand::Value(number, mode))
+            .parse(input)
+            .or_else(|_| map(label(), Operand::Label).parse(input))
+            .map_err(|_| ParseError::new(input, "a value or label"))
     }
 }
 
-pub fn parse_opcode(opcode: &str) -> u8 {
-    match opcode {
+pub fn parse_opcode(opcode: &str) -> Option<u8> {
+    let opcode = match opcode {
         "hlt" => 0x00,
         "ldr" => 0x01,
         "str" => 0x02,
         "set" => 0x03,
         "cpy" => 0x04,
+        "psh" => 0x05,
+        "pop" => 0x06,
+        "setw" => 0x07,
         "jpt" => 0x10,
         "jpf" => 0x11,
+        "cal" => 0x12,
+        "ret" => 0x13,
+        "jmp" => 0x14,
+        "jpa" => 0x15,
+        "jpi" => 0x16,
+        "jfs" => 0x17,
+        "jfc" => 0x18,
         "add" => 0x20,
         "sub" => 0x21,
         "mul" => 0x22,
         "div" => 0x23,
         "mod" => 0x24,
+        "sdv" => 0x25,
+        "smd" => 0x26,
         "eql" => 0x30,
         "neq" => 0x31,
         "let" => 0x32,
         "leq" => 0x33,
         "grt" => 0x34,
         "geq" => 0x35,
+        "slt" => 0x36,
+        "sle" => 0x37,
+        "sgt" => 0x38,
+        "sge" => 0x39,
+        "and" => 0x40,
+        "orr" => 0x41,
+        "xor" => 0x42,
+        "not" => 0x43,
+        "shl" => 0x44,
+        "shr" => 0x45,
+        "sar" => 0x46,
         "sti" => 0x50,
         "sto" => 0x51,
-        _     => panic!("Invalid opcode: {}", opcode),
+        "int" => 0x60,
+        "iret" => 0x61,
+        "eni" => 0x62,
+        "dsi" => 0x63,
+        "wfi" => 0x64,
+        _     => return None,
+    };
+
+    Some(opcode)
+}
+
+pub fn parse_value(value_number: &str, value_mode: &str) -> Result<u8, AsmErrorKind> {
+    let (mode_bits, max) = match value_mode {
+        "i" => (0b1000_0000, 0b0111_1111),
+        "r" => (0b0001_0000, 0b0000_1111),
+        "o" => return parse_offset(value_number).map(|offset| 0b0100_0000 | offset),
+        "s" => (0b0000_0000, 0b0000_1111),
+        _ => return Err(AsmErrorKind::InvalidMode),
+    };
+
+    match parse_literal(value_number) {
+        Some(number) if number <= max => Ok(mode_bits | number as u8),
+        _ => Err(AsmErrorKind::ValueOutOfRange { max }),
+    }
+}
+
+/// A 6-bit two's complement pc-relative offset.
+fn parse_offset(number: &str) -> Result<u8, AsmErrorKind> {
+    let offset = parse_literal(number).map(|offset| offset as i64);
+    match offset {
+        Some(offset) if (-32..=31).contains(&offset) => Ok(offset as u8 & 0b0011_1111),
+        _ => Err(AsmErrorKind::OffsetOutOfRange),
     }
 }
 
-pub fn parse_value(value_number: &str, value_mode: &str) -> u8 {
-    use std::str::FromStr;
+/// A decimal, `0x` hexadecimal, `0b` binary or `'c'` character literal,
+/// optionally negated with a leading `-`.
+pub fn number<'a>() -> impl Parser<'a, String> {
+    move |input: &'a str| {
+        if let Some(rest) = input.strip_prefix('-') {
+            return unsigned_number()
+                .parse(rest)
+                .map(|(rest, number)| (rest, format!("-{}", number)))
+                .map_err(|_| ParseError::new(input, "a number"));
+        }
+        unsigned_number().parse(input)
+    }
+}
 
-    let number = u8::from_str(value_number).expect("Parsing error");
-    let mode_bits = match value_mode {
-        "i" => 0b1000_0000,
-        "r" => 0b0001_0000,
-        "o" => 0b0100_0000,
-        _ => panic!("Invalid mode: {}", value_mode),
+fn unsigned_number<'a>() -> impl Parser<'a, String> {
+    move |input| {
+        let hex = map(
+            pair(literal("0x"), |input| one_or_more(input, |ch: char| ch.is_ascii_hexdigit())),
+            |(prefix, digits)| prefix + &digits,
+        );
+        let binary = map(
+            pair(literal("0b"), |input| one_or_more(input, |ch: char| ch == '0' || ch == '1')),
+            |(prefix, digits)| prefix + &digits,
+        );
+        let decimal = |input| one_or_more(input, |ch: char| ch.is_ascii_digit());
+
+        hex.parse(input)
+            .or_else(|_| binary.parse(input))
+            .or_else(|_| character(input))
+            .or_else(|_| decimal(input))
+            .map_err(|_| ParseError::new(input, "a number"))
+    }
+}
+
+/// A single quoted character, which may be a `\n`, `\t`, `\r`, `\0`, `\\` or `\'` escape.
+fn character(input: &str) -> ParseResult<'_, String> {
+    let mut chars = input.char_indices();
+    let end = match (chars.next(), chars.next(), chars.next()) {
+        (Some((_, '\'')), Some((_, '\\')), Some(_)) => chars.next(),
+        (Some((_, '\'')), Some((_, ch)), next) if ch != '\'' => next,
+        _ => None,
     };
 
-    mode_bits | number
+    match end {
+        Some((index, '\'')) => Ok((&input[index + 1..], input[..=index].to_string())),
+        _ => Err(ParseError::new(input, "a character")),
+    }
 }
 
-pub fn parse_instruction(instruction: &str) -> [u8; 4] {
-    let (rest, opcode) = opcode().parse(instruction).expect("Parsing error");
-    let opcode = parse_opcode(&opcode);
-    if opcode == 0 {
-        return [opcode, 0, 0, 0];
+/// Returns the value of a literal accepted by [`number`], or `None` if it
+/// doesn't fit in 64 bits. Negative numbers are two's complement.
+pub fn parse_literal(number: &str) -> Option<u64> {
+    if let Some(magnitude) = number.strip_prefix('-') {
+        parse_literal(magnitude)
+            .filter(|magnitude| *magnitude <= 1 << 63)
+            .map(u64::wrapping_neg)
+    } else if let Some(hex) = number.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = number.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()
+    } else if let Some(quoted) = number.strip_prefix('\'') {
+        let value = match quoted.strip_suffix('\'')? {
+            "\\n" => '\n',
+            "\\t" => '\t',
+            "\\r" => '\r',
+            "\\0" => '\0',
+            "\\\\" => '\\',
+            "\\'" => '\'',
+            ch if ch.chars().count() == 1 => ch.chars().next()?,
+            _ => return None,
+        };
+        Some(value as u64)
+    } else {
+        number.parse().ok()
     }
+}
 
-    let (rest, _) = literal(" ").parse(rest).expect("Parsing error");
-    let (rest, arg1) = value().parse(rest).expect("Parsing error");
-    let (rest, _) = literal(" ").parse(rest).expect("Parsing error");
-    let (rest, arg2) = value().parse(rest).expect("Parsing error");
-    let (rest, _) = optional(literal(" ")).parse(rest).expect("Parsing error");
-    let (_, arg3) = optional(value()).parse(rest).expect("Parsing error");
+/// The number of ROM words after a `setw` needed to hold `value`.
+pub fn wide_words(value: u64) -> u8 {
+    if value > u32::MAX as u64 { 2 } else { 1 }
+}
+
+/// `.word` followed by a raw 32-bit instruction.
+pub fn word_directive<'a>() -> impl Parser<'a, String> {
+    move |input| {
+        let (rest, _) = literal_ignore_case(".word").parse(input)?;
+        let (rest, _) = whitespace().parse(rest)?;
+        number().parse(rest)
+    }
+}
 
-    let dest = parse_value(&arg1.0, &arg1.1);
-    let op1 = parse_value(&arg2.0, &arg2.1);
-    
-    let op2 = match arg3 {
-        Some(arg) => parse_value(&arg.0, &arg.1),
-        None => 0,
+pub fn parse_word(number: &str) -> Result<u32, AsmErrorKind> {
+    match parse_literal(number) {
+        Some(word) if word <= u32::MAX as u64 => Ok(word as u32),
+        _ => Err(AsmErrorKind::ValueOutOfRange { max: u32::MAX as u64 }),
+    }
+}
+
+/// A line of assembly whose operands may still refer to labels.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement<'a> {
+    Instruction {
+        opcode: u8,
+        /// Each operand along with the input starting at it, for error reporting.
+        operands: Vec<(&'a str, Operand)>,
+    },
+    /// A raw instruction word, along with the input starting at it.
+    Word(&'a str, String),
+}
+
+/// Parses a single instruction with up to three operands separated by
+/// whitespace, or a `.word` directive, ignoring any trailing comment.
+pub fn parse_statement(instruction: &str) -> Result<Statement<'_>, ParseError<'_>> {
+    if let Ok((rest, word)) = word_directive().parse(instruction) {
+        end_of_line().parse(rest)?;
+        let start = &instruction[instruction.len() - rest.len() - word.len()..];
+        return Ok(Statement::Word(start, word));
+    }
+
+    let (mut rest, opcode) = opcode().parse(instruction)?;
+    let opcode = parse_opcode(&opcode).ok_or_else(|| ParseError::new(instruction, "an opcode"))?;
+
+    let mut operands = Vec::new();
+    while operands.len() < 3 {
+        let start = match whitespace().parse(rest) {
+            Ok((start, _)) => start,
+            Err(_) => break,
+        };
+        match operand().parse(start) {
+            Ok((next, operand)) => {
+                operands.push((start, operand));
+                rest = next;
+            },
+            Err(_) => break,
+        }
+    }
+
+    if end_of_line().parse(rest).is_err() {
+        let expected = if operands.len() < 3 { "a value, label or end of line" } else { "end of line" };
+        return Err(ParseError::new(rest.trim_start(), expected));
+    }
+
+    Ok(Statement::Instruction { opcode, operands })
+}
+
+/// Returns true for lines with nothing to assemble.
+pub fn is_blank(line: &str) -> bool {
+    end_of_line().parse(line).is_ok()
+}
+
+pub fn parse_instruction(instruction: &str) -> Result<[u8; 4], AsmError> {
+    let statement = parse_statement(instruction)
+        .map_err(|error| AsmError::from_parse_error(1, instruction, error))?;
+
+    let (opcode, operands) = match statement {
+        Statement::Instruction { opcode, operands } => (opcode, operands),
+        Statement::Word(start, word) => {
+            return parse_word(&word)
+                .map(u32::to_be_bytes)
+                .map_err(|kind| AsmError::new(1, instruction, start, kind));
+        },
     };
 
-    [ opcode, dest, op1, op2 ]
+    let mut bytes = [opcode, 0, 0, 0];
+    for (byte, (start, operand)) in bytes[1..].iter_mut().zip(operands) {
+        let kind = match operand {
+            Operand::Value(number, mode) => match parse_value(&number, &mode) {
+                Ok(value) => {
+                    *byte = value;
+                    continue;
+                },
+                Err(kind) => kind,
+            },
+            Operand::Label(_) => AsmErrorKind::InvalidLabelUse,
+        };
+        return Err(AsmError::new(1, instruction, start, kind));
+    }
+
+    Ok(bytes)
 }
 
 #[cfg(test)]
@@ -120,7 +383,7 @@ mod tests {
     #[test]
     fn value_parser() {
         assert_eq!(opcode().parse("hlt"), Ok(("", String::from("hlt"))));
-        assert_eq!(opcode().parse("taco"), Err("taco"));
+        assert_eq!(opcode().parse("taco"), Err(ParseError::new("taco", "an opcode")));
     }
 
     #[test]
@@ -128,21 +391,92 @@ mod tests {
         assert_eq!(value().parse("123i"), Ok(("", (String::from("123"), String::from("i")))));
         assert_eq!(value().parse("123"), Ok(("", (String::from("123"), String::from("i")))));
         assert_eq!(value().parse("123b"), Ok(("", (String::from("123"), String::from("b")))));
-        assert_eq!(value().parse("i"), Err("i"));
+        assert_eq!(value().parse("i"), Err(ParseError::new("i", "a value")));
     }
 
     #[test]
     fn instruction_parser() {
         let i1 = parse_instruction("hlt");
-        assert_eq!(i1, [0, 0, 0, 0]);
+        assert_eq!(i1, Ok([0, 0, 0, 0]));
 
         let i2 = parse_instruction("ldr 0 1");
-        assert_eq!(i2, [1, 128, 129, 0]);
+        assert_eq!(i2, Ok([1, 128, 129, 0]));
 
         let i3 = parse_instruction("str 0 1");
-        assert_eq!(i3, [2, 128, 129, 0]);
+        assert_eq!(i3, Ok([2, 128, 129, 0]));
         
         let i4 = parse_instruction("add 0 1 2");
-        assert_eq!(i4, [32, 128, 129, 130]);
+        assert_eq!(i4, Ok([32, 128, 129, 130]));
+
+        let i5 = parse_instruction("sto 0");
+        assert_eq!(i5, Ok([0x51, 128, 0, 0]));
+
+        let i6 = parse_instruction("add 3r 2s 15s");
+        assert_eq!(i6, Ok([32, 0x13, 0x02, 0x0F]));
+
+        let i7 = parse_instruction(".word 0xaa112233");
+        assert_eq!(i7, Ok([0xAA, 0x11, 0x22, 0x33]));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse_literal("42"), Some(42));
+        assert_eq!(parse_literal("0x2A"), Some(42));
+        assert_eq!(parse_literal("0b101010"), Some(42));
+        assert_eq!(parse_literal("'*'"), Some(42));
+        assert_eq!(parse_literal("'\\n'"), Some(10));
+        assert_eq!(parse_literal("18446744073709551616"), None);
+        assert_eq!(parse_literal("-5"), Some(-5i64 as u64));
+        assert_eq!(parse_literal("-0x8000000000000000"), Some(i64::MIN as u64));
+        assert_eq!(parse_literal("-0x8000000000000001"), None);
+
+        assert_eq!(value().parse("'a'r"), Ok(("", (String::from("'a'"), String::from("r")))));
+        assert_eq!(value().parse("' '"), Ok(("", (String::from("' '"), String::from("i")))));
+        assert_eq!(parse_instruction("set 0 'a'"), Ok([0x03, 128, 0x80 | 97, 0]));
+        assert_eq!(parse_instruction("add 0b11r 0x1 0"), Ok([0x20, 0x13, 129, 128]));
+        assert_eq!(value().parse("-5"), Ok(("", (String::from("-5"), String::from("i")))));
+        assert_eq!(parse_instruction("ldr 0 -1o 31o"), Ok([0x01, 128, 0x7F, 0x5F]));
+        assert_eq!(error_at("add 0 -1 2"), (7, String::from("-1"), AsmErrorKind::ValueOutOfRange { max: 127 }));
+    }
+
+    #[test]
+    fn instruction_formatting() {
+        assert_eq!(parse_instruction("ADD\t0   1r\t 2 ; add things"), Ok([32, 128, 17, 130]));
+        assert_eq!(parse_instruction("Jpt 3 3r 0 # loop"), Ok([0x10, 131, 0x13, 128]));
+        assert_eq!(parse_instruction("hlt;"), Ok([0, 0, 0, 0]));
+        assert!(is_blank("   # just a comment"));
+        assert!(is_blank("\t; another comment"));
+        assert!(is_blank(""));
+        assert!(!is_blank("hlt"));
+    }
+
+    fn error_at(instruction: &str) -> (usize, String, AsmErrorKind) {
+        let error = parse_instruction(instruction).unwrap_err();
+        (error.column, error.token, error.kind)
+    }
+
+    #[test]
+    fn instruction_errors() {
+        let syntax = |expected: &str| AsmErrorKind::Syntax { expected: String::from(expected) };
+
+        assert_eq!(error_at("sot 1"), (1, String::from("sot"), syntax("an opcode")));
+        assert_eq!(error_at("add 0 1 2 3"), (11, String::from("3"), syntax("end of line")));
+        assert_eq!(error_at("add 0 ! 2"), (7, String::from("!"), syntax("a value, label or end of line")));
+        assert_eq!(error_at("set 0 300"), (7, String::from("300"), AsmErrorKind::ValueOutOfRange { max: 127 }));
+        assert_eq!(error_at("set 16r 1"), (5, String::from("16r"), AsmErrorKind::ValueOutOfRange { max: 15 }));
+        assert_eq!(error_at("set 32o 1"), (5, String::from("32o"), AsmErrorKind::OffsetOutOfRange));
+        assert_eq!(error_at("set -33o 1"), (5, String::from("-33o"), AsmErrorKind::OffsetOutOfRange));
+        assert_eq!(error_at(".word 0x100000000"), (7, String::from("0x100000000"), AsmErrorKind::ValueOutOfRange { max: 0xFFFF_FFFF }));
+        assert_eq!(error_at("set 0 5x"), (7, String::from("5x"), AsmErrorKind::InvalidMode));
+        assert_eq!(error_at("jpt loop 1"), (5, String::from("loop"), AsmErrorKind::InvalidLabelUse));
+    }
+
+    #[test]
+    fn error_display() {
+        let error = parse_instruction("add 0 1 2 y").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1, column 11: expected end of line, found `y`\n  | add 0 1 2 y\n  |           ^",
+        );
     }
 }
@@ -0,0 +1,327 @@
+//! The `.gorp` object file format.
+//!
+//! All integers are little-endian. Instructions are stored as their four
+//! bytes in `[opcode, dest, op1, op2]` order, so a ROM word read as a
+//! number, like the constants after a `setw`, is big-endian. Data words are
+//! 64 bits, the width of a machine word.
+//!
+//! ```text
+//! offset  size  field
+//! 0       4     magic "GORP"
+//! 4       2     format version
+//! 6       2     register count
+//! 8       4     entry point (ROM index)
+//! 12      4     ROM length in instructions
+//! 16      4     data segment length in words
+//! 20      4     symbol count
+//! 24      4     FNV-1a checksum of the whole file, reading this field as zero
+//! 28      4     memory size in words
+//! 32      4     stack size in words
+//! 36      4     maximum ROM length in instructions
+//! 40      8     maximum cycles
+//! 48      4     flags, bit 0 set to load code into memory at the code base
+//! 52      4     code base (memory address)
+//! 56      4     interrupt vector table (memory address)
+//! 60      ...   ROM, 4 bytes per instruction
+//!         ...   data segment, 8 bytes per word, loaded at memory address 0
+//!         ...   symbols, each a 4 byte ROM index, 2 byte name length and UTF-8 name
+//! ```
+//!
+//! The register count and everything from offset 28 to 60 make up the
+//! [`Profile`], where 0 means the loader's default. The code base is only
+//! used when flag bit 0 is set, otherwise code stays in a separate ROM.
+
+pub const MAGIC: [u8; 4] = *b"GORP";
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 60;
+const VON_NEUMANN: u32 = 1;
+const CHECKSUM_OFFSET: usize = 24;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The file ended before everything the header describes.
+    Truncated,
+    /// There are bytes after the end of the symbol table.
+    TrailingBytes(usize),
+    ChecksumMismatch { expected: u32, found: u32 },
+    InvalidSymbol,
+    EntryOutOfBounds(u32),
+    /// A raw ROM image whose length isn't a multiple of 4.
+    PartialInstruction { length: usize },
+    /// The data segment doesn't fit in the memory of the machine loading it.
+    DataTooLarge { words: usize, capacity: usize },
+    /// The ROM is longer than the machine loading it allows.
+    RomTooLarge { instructions: usize, capacity: usize },
+    /// The program's labels were placed for a different code base than the
+    /// machine loading it uses.
+    CodeBaseMismatch { assembled: usize, loaded: usize },
+}
+
+impl std::fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::BadMagic => write!(f, "not a gorp object file"),
+            ObjectError::UnsupportedVersion(version) => write!(f, "unsupported object format version {}", version),
+            ObjectError::Truncated => write!(f, "object file is truncated"),
+            ObjectError::TrailingBytes(count) => write!(f, "object file has {} unexpected trailing bytes", count),
+            ObjectError::ChecksumMismatch { expected, found } => {
+                write!(f, "checksum mismatch: expected {:08x}, found {:08x}", expected, found)
+            },
+            ObjectError::InvalidSymbol => write!(f, "object file has an invalid symbol name"),
+            ObjectError::EntryOutOfBounds(entry) => write!(f, "entry point {} is outside the ROM", entry),
+            ObjectError::PartialInstruction { length } => {
+                write!(f, "{} bytes is not a whole number of 4 byte instructions", length)
+            },
+            ObjectError::DataTooLarge { words, capacity } => {
+                write!(f, "data segment of {} words does not fit in {} words of memory", words, capacity)
+            },
+            ObjectError::RomTooLarge { instructions, capacity } => {
+                write!(f, "ROM of {} instructions is longer than the limit of {}", instructions, capacity)
+            },
+            ObjectError::CodeBaseMismatch { assembled, loaded } => {
+                write!(f, "program assembled for code base {} cannot be loaded at code base {}", assembled, loaded)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+/// The machine a program was built for. Fields left at 0 use the loader's default.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Profile {
+    pub registers: u16,
+    pub memory_words: u32,
+    pub stack_words: u32,
+    pub max_rom: u32,
+    pub max_cycles: u64,
+    /// Where to load code in memory, or `None` to keep it in a separate ROM.
+    pub code_base: Option<u32>,
+    pub vector_table: u32,
+}
+
+/// An assembled program.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Object {
+    pub entry: u32,
+    pub rom: Vec<[u8; 4]>,
+    pub data: Vec<u64>,
+    pub symbols: Vec<(String, u32)>,
+    pub profile: Profile,
+}
+
+impl Object {
+    pub fn new(rom: Vec<[u8; 4]>) -> Self {
+        Self {
+            rom,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for instruction in &self.rom {
+            body.extend_from_slice(instruction);
+        }
+        for word in &self.data {
+            body.extend_from_slice(&word.to_le_bytes());
+        }
+        for (name, address) in &self.symbols {
+            body.extend_from_slice(&address.to_le_bytes());
+            body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            body.extend_from_slice(name.as_bytes());
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.profile.registers.to_le_bytes());
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        bytes.extend_from_slice(&(self.rom.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&self.profile.memory_words.to_le_bytes());
+        bytes.extend_from_slice(&self.profile.stack_words.to_le_bytes());
+        bytes.extend_from_slice(&self.profile.max_rom.to_le_bytes());
+        bytes.extend_from_slice(&self.profile.max_cycles.to_le_bytes());
+        let flags = if self.profile.code_base.is_some() { VON_NEUMANN } else { 0 };
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend_from_slice(&self.profile.code_base.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&self.profile.vector_table.to_le_bytes());
+        bytes.extend_from_slice(&body);
+        let checksum = file_checksum(&bytes);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ObjectError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4).map_err(|_| ObjectError::BadMagic)? != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let registers = reader.u16()?;
+
+        let entry = reader.u32()?;
+        let rom_len = reader.u32()? as usize;
+        let data_len = reader.u32()? as usize;
+        let symbol_count = reader.u32()? as usize;
+        let expected = reader.u32()?;
+
+        let memory_words = reader.u32()?;
+        let stack_words = reader.u32()?;
+        let max_rom = reader.u32()?;
+        let max_cycles = reader.u64()?;
+        let flags = reader.u32()?;
+        let code_base = Some(reader.u32()?).filter(|_| flags & VON_NEUMANN != 0);
+        let vector_table = reader.u32()?;
+        let profile = Profile { registers, memory_words, stack_words, max_rom, max_cycles, code_base, vector_table };
+
+        let found = file_checksum(bytes);
+        if expected != found {
+            return Err(ObjectError::ChecksumMismatch { expected, found });
+        }
+
+        let mut rom = Vec::with_capacity(rom_len.min(bytes.len() / 4));
+        for _ in 0..rom_len {
+            let instruction = reader.take(4)?;
+            rom.push([instruction[0], instruction[1], instruction[2], instruction[3]]);
+        }
+        if entry as usize > rom.len() {
+            return Err(ObjectError::EntryOutOfBounds(entry));
+        }
+
+        let mut data = Vec::with_capacity(data_len.min(bytes.len() / 8));
+        for _ in 0..data_len {
+            data.push(reader.u64()?);
+        }
+
+        let mut symbols = Vec::with_capacity(symbol_count.min(bytes.len() / 6));
+        for _ in 0..symbol_count {
+            let address = reader.u32()?;
+            let name_len = reader.u16()? as usize;
+            let name = std::str::from_utf8(reader.take(name_len)?)
+                .map_err(|_| ObjectError::InvalidSymbol)?;
+            symbols.push((name.to_string(), address));
+        }
+
+        let remaining = bytes.len() - reader.position;
+        if remaining > 0 {
+            return Err(ObjectError::TrailingBytes(remaining));
+        }
+
+        Ok(Self { entry, rom, data, symbols, profile })
+    }
+}
+
+/// Returns true if `bytes` start with the object file magic number.
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// 32-bit FNV-1a.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// The checksum of a whole object file, reading the checksum field as zero.
+fn file_checksum(bytes: &[u8]) -> u32 {
+    let (header, rest) = bytes.split_at(CHECKSUM_OFFSET + 4);
+    checksum(&[&header[..CHECKSUM_OFFSET], &[0; 4], rest].concat())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ObjectError> {
+        let end = self.position.checked_add(count).ok_or(ObjectError::Truncated)?;
+        let taken = self.bytes.get(self.position..end).ok_or(ObjectError::Truncated)?;
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, ObjectError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let mut buffer = [0; 4];
+        buffer.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    fn u64(&mut self) -> Result<u64, ObjectError> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> Object {
+        Object {
+            entry: 1,
+            rom: vec![[0x03, 128, 129, 0], [0x20, 129, 0x10, 0x11], [0, 0, 0, 0]],
+            data: vec![7, u64::MAX],
+            symbols: vec![(String::from("loop"), 1)],
+            profile: Profile {
+                registers: 8,
+                memory_words: 1024,
+                max_cycles: 500,
+                code_base: Some(16),
+                vector_table: 100,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = object().to_bytes();
+        assert_eq!(&bytes[0..4], b"GORP");
+        assert_eq!(bytes.len(), HEADER_LEN + 3 * 4 + 2 * 8 + 4 + 2 + 4);
+        assert_eq!(Object::from_bytes(&bytes), Ok(object()));
+    }
+
+    #[test]
+    fn validation() {
+        let bytes = object().to_bytes();
+
+        assert_eq!(Object::from_bytes(b"ELF!"), Err(ObjectError::BadMagic));
+        assert_eq!(Object::from_bytes(&bytes[..HEADER_LEN - 1]), Err(ObjectError::Truncated));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(Object::from_bytes(&version), Err(ObjectError::UnsupportedVersion(9)));
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN] ^= 0xFF;
+        assert!(matches!(Object::from_bytes(&corrupted), Err(ObjectError::ChecksumMismatch { .. })));
+
+        let mut entry = bytes.clone();
+        entry[8] ^= 1;
+        assert!(matches!(Object::from_bytes(&entry), Err(ObjectError::ChecksumMismatch { .. })));
+
+        let mut memory = bytes.clone();
+        memory[28..32].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(Object::from_bytes(&memory), Err(ObjectError::ChecksumMismatch { .. })));
+
+        let mut short = object();
+        short.entry = 4;
+        assert_eq!(Object::from_bytes(&short.to_bytes()), Err(ObjectError::EntryOutOfBounds(4)));
+    }
+}
@@ -1,7 +1,24 @@
 #![allow(dead_code)]
 // Following https://bodil.lol/parser-combinators/
 
-pub type ParseResult<'a, Output> = Result<(&'a str, Output), &'a str>;
+pub type ParseResult<'a, Output> = Result<(&'a str, Output), ParseError<'a>>;
+
+/// Where a parser failed and what it was looking for.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError<'a> {
+    /// The input remaining at the point of failure.
+    pub rest: &'a str,
+    pub expected: String,
+}
+
+impl<'a> ParseError<'a> {
+    pub fn new<S: Into<String>>(rest: &'a str, expected: S) -> Self {
+        Self {
+            rest,
+            expected: expected.into(),
+        }
+    }
+}
 
 pub trait Parser<'a, Output> {
     fn parse(&self, input: &'a str) -> ParseResult<'a, Output>;
@@ -9,7 +26,7 @@ pub trait Parser<'a, Output> {
 
 impl<'a, F, Output> Parser<'a, Output> for F
 where
-    F: Fn(&'a str) -> ParseResult<Output>,
+    F: Fn(&'a str) -> ParseResult<'a, Output>,
 {
     fn parse(&self, input: &'a str) -> ParseResult<'a, Output> {
         self(input)
@@ -19,28 +36,33 @@ where
 pub fn literal<'a>(expected: &'static str) -> impl Parser<'a, String> {
     move |input: &'a str| match input.get(0..expected.len()) {
         Some(next) if next == expected => Ok((&input[expected.len()..], expected.to_string())),
-        _ => Err(input)
+        _ => Err(ParseError::new(input, format!("`{}`", expected))),
     }
 }
 
-pub fn predicate<F>(input: &str, predicate: F) -> ParseResult<String> 
+pub fn literal_ignore_case<'a>(expected: &'static str) -> impl Parser<'a, String> {
+    move |input: &'a str| match input.get(0..expected.len()) {
+        Some(next) if next.eq_ignore_ascii_case(expected) => Ok((&input[expected.len()..], expected.to_string())),
+        _ => Err(ParseError::new(input, format!("`{}`", expected))),
+    }
+}
+
+pub fn predicate<F>(input: &str, predicate: F) -> ParseResult<'_, String>
 where
     F: Fn(char) -> bool,
 {
-    match input.chars().nth(0) {
-        Some(ch) if predicate(ch) => Ok((&input[1..], ch.to_string())),
-        _ => Err(input),
+    match input.chars().next() {
+        Some(ch) if predicate(ch) => Ok((&input[ch.len_utf8()..], ch.to_string())),
+        _ => Err(ParseError::new(input, "a matching character")),
     }
 }
 
-pub fn zero_or_more<F>(input: &str, predicate: F) -> ParseResult<String> 
+pub fn zero_or_more<F>(input: &str, predicate: F) -> ParseResult<'_, String>
 where
     F: Fn(char) -> bool,
 {
     let mut matched = String::new();
-    let mut chars = input.chars();
-
-    while let Some(next) = chars.next() {
+    for next in input.chars() {
         if predicate(next) {
             matched.push(next);
         } else {
@@ -52,14 +74,12 @@ where
     Ok((&input[next_index..], matched))
 }
 
-pub fn one_or_more<F>(input: &str, predicate: F) -> ParseResult<String> 
+pub fn one_or_more<F>(input: &str, predicate: F) -> ParseResult<'_, String>
 where
     F: Fn(char) -> bool,
 {
     let mut matched = String::new();
-    let mut chars = input.chars();
-
-    while let Some(next) = chars.next() {
+    for next in input.chars() {
         if predicate(next) {
             matched.push(next);
         } else {
@@ -68,13 +88,38 @@ where
     }
 
     if matched.is_empty() {
-        Err(input)
+        Err(ParseError::new(input, "a matching character"))
     } else {
         let next_index = matched.len();
         Ok((&input[next_index..], matched))
     }
 }
 
+pub fn whitespace<'a>() -> impl Parser<'a, String> {
+    describe(move |input| one_or_more(input, char::is_whitespace), "whitespace")
+}
+
+/// A `;` or `#` comment running to the end of the line.
+pub fn comment<'a>() -> impl Parser<'a, String> {
+    move |input: &'a str| match input.chars().next() {
+        Some(';') | Some('#') => Ok(("", input[1..].to_string())),
+        _ => Err(ParseError::new(input, "a comment")),
+    }
+}
+
+/// Trailing whitespace and an optional comment, with nothing after them.
+pub fn end_of_line<'a>() -> impl Parser<'a, ()> {
+    move |input: &'a str| {
+        let (rest, _) = optional(whitespace()).parse(input)?;
+        let (rest, _) = optional(comment()).parse(rest)?;
+        if rest.is_empty() {
+            Ok((rest, ()))
+        } else {
+            Err(ParseError::new(rest, "end of line"))
+        }
+    }
+}
+
 pub fn pair<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, (R1, R2)>
 where
     P1: Parser<'a, R1>,
@@ -84,11 +129,27 @@ where
         parser1.parse(input)
             .and_then(|(next_input, result1)| {
                 parser2.parse(next_input)
-                    .map(|(last_input, result2)| (last_input, (result1, result2))) 
+                    .map(|(last_input, result2)| (last_input, (result1, result2)))
             })
     }
 }
 
+pub fn left<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, R1>
+where
+    P1: Parser<'a, R1>,
+    P2: Parser<'a, R2>,
+{
+    map(pair(parser1, parser2), |(left, _right)| left)
+}
+
+pub fn right<'a, P1, P2, R1, R2>(parser1: P1, parser2: P2) -> impl Parser<'a, R2>
+where
+    P1: Parser<'a, R1>,
+    P2: Parser<'a, R2>,
+{
+    map(pair(parser1, parser2), |(_left, right)| right)
+}
+
 pub fn map<'a, P, F, A, B>(parser: P, map_fn: F) -> impl Parser<'a, B>
 where
     P: Parser<'a, A>,
@@ -101,20 +162,32 @@ where
     }
 }
 
-pub fn one_of<'a, P, R>(parsers: Vec<P>) -> impl Parser<'a, R> 
+/// Replaces a parser's error with a description of the whole thing it parses.
+pub fn describe<'a, P, R>(parser: P, expected: &'static str) -> impl Parser<'a, R>
 where
-    P: Parser<'a, R>
+    P: Parser<'a, R>,
 {
     move |input| {
-        let mut parsers = parsers.iter();
+        parser
+            .parse(input)
+            .map_err(|_| ParseError::new(input, expected))
+    }
+}
 
-        while let Some(parser) = parsers.next() {
-            if let Ok(result) = parser.parse(input) {
-                return Ok(result);
+pub fn one_of<'a, P, R>(parsers: Vec<P>) -> impl Parser<'a, R>
+where
+    P: Parser<'a, R>
+{
+    move |input| {
+        let mut expected = Vec::new();
+        for parser in parsers.iter() {
+            match parser.parse(input) {
+                Ok(result) => return Ok(result),
+                Err(error) => expected.push(error.expected),
             }
         }
 
-        Err(input)
+        Err(ParseError::new(input, expected.join(" or ")))
     }
 }
 
@@ -125,7 +198,31 @@ where
     move |input| {
         match parser.parse(input) {
             Ok((rest, result)) => Ok((rest, Some(result))),
-            Err(input) => Ok((input, None)),
+            Err(_) => Ok((input, None)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_point_at_failure() {
+        let parser = pair(literal("ab"), right(whitespace(), literal("cd")));
+        assert_eq!(parser.parse("ab  ce"), Err(ParseError::new("ce", "`cd`")));
+        assert_eq!(parser.parse("abcd"), Err(ParseError::new("cd", "whitespace")));
+    }
+
+    #[test]
+    fn one_of_lists_alternatives() {
+        let parser = one_of(vec![literal("ab"), literal("cd")]);
+        assert_eq!(parser.parse("ef"), Err(ParseError::new("ef", "`ab` or `cd`")));
+    }
+
+    #[test]
+    fn end_of_line_allows_comments() {
+        assert!(end_of_line().parse("  ; comment").is_ok());
+        assert_eq!(end_of_line().parse("  x # comment"), Err(ParseError::new("x # comment", "end of line")));
+    }
+}
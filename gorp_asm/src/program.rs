@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use crate::{
+    AsmError, AsmErrorKind, Operand, Statement, is_blank, label_definition, parse_literal, parse_statement,
+    parse_value, parse_word, wide_words,
+};
+use crate::object::Object;
+use crate::parser::Parser;
+
+/// Opcodes whose first operand is a distance relative to `pc` and whose
+/// third operand says which direction to jump in.
+const RELATIVE_JUMPS: &[u8] = &[0x10, 0x11, 0x12, 0x14, 0x17, 0x18];
+
+/// Opcodes whose first operand is an absolute ROM index.
+const ABSOLUTE_JUMPS: &[u8] = &[0x15];
+
+const SET: u8 = 0x03;
+const SET_WIDE: u8 = 0x07;
+
+struct Line<'a> {
+    number: usize,
+    source: &'a str,
+    statement: Statement<'a>,
+}
+
+/// Assembles a whole program, resolving `label:` definitions used as jump
+/// targets or `set` values.
+///
+/// Every line is checked, so the error list covers the whole program rather
+/// than stopping at the first problem.
+pub fn parse_program(source: &str) -> Result<Vec<[u8; 4]>, Vec<AsmError>> {
+    assemble_object(source).map(|object| object.rom)
+}
+
+/// Assembles a program into a `.gorp` object file.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<AsmError>> {
+    assemble_object(source).map(|object| object.to_bytes())
+}
+
+/// Assembles a program, keeping its labels as the object's symbol table.
+pub fn assemble_object(source: &str) -> Result<Object, Vec<AsmError>> {
+    assemble_object_at(source, 0)
+}
+
+/// Assembles a program to run in von Neumann mode from `code_base`, so
+/// labels used as addresses point at the code in memory. The symbol table
+/// still holds ROM indices.
+pub fn assemble_object_at(source: &str, code_base: usize) -> Result<Object, Vec<AsmError>> {
+    let mut errors = Vec::new();
+    // Each label maps to the line it precedes until the layout is known
+    let mut labels = HashMap::new();
+    let mut lines = Vec::new();
+
+    for (index, source_line) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut rest = source_line.trim_start();
+        while let Ok((next, label)) = label_definition().parse(rest) {
+            match labels.entry(label) {
+                Entry::Occupied(_) => {
+                    errors.push(AsmError::new(number, source_line, rest, AsmErrorKind::DuplicateLabel));
+                },
+                Entry::Vacant(entry) => {
+                    entry.insert(lines.len());
+                },
+            }
+            rest = next.trim_start();
+        }
+
+        if is_blank(rest) {
+            continue;
+        }
+
+        match parse_statement(rest) {
+            Ok(statement) => lines.push(Line { number, source: source_line, statement }),
+            Err(error) => errors.push(AsmError::from_parse_error(number, source_line, error)),
+        }
+    }
+
+    let addresses = layout(&lines, &labels, code_base);
+    for line in labels.values_mut() {
+        *line = addresses[*line];
+    }
+
+    let mut instructions = Vec::with_capacity(addresses[lines.len()] - code_base);
+    for line in lines {
+        match resolve(code_base + instructions.len(), line, &labels) {
+            Ok(words) => instructions.extend(words),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    if errors.is_empty() {
+        let mut symbols: Vec<(String, u32)> = labels
+            .into_iter()
+            .map(|(label, address)| (label, (address - code_base) as u32))
+            .collect();
+        symbols.sort_by_key(|(label, index)| (*index, label.clone()));
+
+        Ok(Object {
+            symbols,
+            ..Object::new(instructions)
+        })
+    } else {
+        errors.sort_by_key(|error| (error.line, error.column));
+        Err(errors)
+    }
+}
+
+/// The value of a `set` whose immediate is too big for one instruction.
+fn wide_set(opcode: u8, operands: &[(&str, Operand)]) -> Option<u64> {
+    match operands.get(1) {
+        Some((_, Operand::Value(number, mode))) if opcode == SET && mode == "i" => {
+            parse_literal(number).filter(|value| *value > 0b0111_1111)
+        },
+        _ => None,
+    }
+}
+
+/// The label a `set` loads the address of.
+fn set_label<'a>(opcode: u8, operands: &'a [(&str, Operand)]) -> Option<&'a str> {
+    match operands.get(1) {
+        Some((_, Operand::Label(label))) if opcode == SET => Some(label),
+        _ => None,
+    }
+}
+
+/// The number of ROM words a statement assembles to, counting a `set` of a
+/// label as a single word.
+fn size(statement: &Statement<'_>) -> usize {
+    match statement {
+        Statement::Instruction { opcode, operands } => {
+            1 + wide_set(*opcode, operands).map_or(0, |value| wide_words(value) as usize)
+        },
+        Statement::Word(..) => 1,
+    }
+}
+
+/// The address of each line, counting from `code_base`, followed by the
+/// address after the last one.
+///
+/// A `set` of a label past 127 becomes a `setw`, which can push later labels
+/// further out, so sizes grow until every label settles.
+fn layout(lines: &[Line<'_>], labels: &HashMap<String, usize>, code_base: usize) -> Vec<usize> {
+    let mut sizes: Vec<usize> = lines.iter().map(|line| size(&line.statement)).collect();
+    loop {
+        let mut addresses = Vec::with_capacity(lines.len() + 1);
+        let mut address = code_base;
+        for size in &sizes {
+            addresses.push(address);
+            address += size;
+        }
+        addresses.push(address);
+
+        let mut grown = false;
+        for (line, size) in lines.iter().zip(&mut sizes) {
+            let label = match &line.statement {
+                Statement::Instruction { opcode, operands } => set_label(*opcode, operands),
+                Statement::Word(..) => None,
+            };
+            let target = match label.and_then(|label| labels.get(label)) {
+                Some(line) => addresses[*line],
+                None => continue,
+            };
+            let needed = if target > 0b0111_1111 { 1 + wide_words(target as u64) as usize } else { 1 };
+            if needed > *size {
+                *size = needed;
+                grown = true;
+            }
+        }
+        if !grown {
+            return addresses;
+        }
+    }
+}
+
+/// Assembles one line into its ROM words. A `set` with a wide immediate
+/// becomes a `setw` followed by the value, most significant word first.
+fn resolve(index: usize, line: Line<'_>, labels: &HashMap<String, usize>) -> Result<Vec<[u8; 4]>, AsmError> {
+    let Line { number, source, statement } = line;
+    let (opcode, mut operands) = match statement {
+        Statement::Instruction { opcode, operands } => (opcode, operands),
+        Statement::Word(start, word) => {
+            return parse_word(&word)
+                .map(|word| vec![word.to_be_bytes()])
+                .map_err(|kind| AsmError::new(number, source, start, kind));
+        },
+    };
+
+    if let Some(label) = set_label(opcode, &operands) {
+        let target = *labels.get(label)
+            .ok_or_else(|| AsmError::new(number, source, operands[1].0, AsmErrorKind::UndefinedLabel))?;
+        operands[1].1 = Operand::Value(target.to_string(), String::from("i"));
+    }
+
+    let mut extra = Vec::new();
+    let opcode = match wide_set(opcode, &operands) {
+        Some(value) => {
+            let count = wide_words(value);
+            for word in (0..count).rev() {
+                extra.push(((value >> (32 * word as u32)) as u32).to_be_bytes());
+            }
+            operands[1].1 = Operand::Value(count.to_string(), String::from("i"));
+            SET_WIDE
+        },
+        None => opcode,
+    };
+    let mut bytes = [opcode, 0, 0, 0];
+
+    for (position, (start, operand)) in operands.into_iter().enumerate() {
+        let error = |kind| AsmError::new(number, source, start, kind);
+        match operand {
+            Operand::Value(number, mode) => {
+                bytes[position + 1] = parse_value(&number, &mode).map_err(error)?;
+            },
+            Operand::Label(label) if position == 0 && RELATIVE_JUMPS.contains(&opcode) => {
+                let target = *labels.get(&label).ok_or_else(|| error(AsmErrorKind::UndefinedLabel))?;
+                let (distance, sign) = relative_jump(index, target)
+                    .ok_or_else(|| error(AsmErrorKind::JumpOutOfRange))?;
+                bytes[1] = 0b1000_0000 | distance;
+                bytes[3] = 0b1000_0000 | sign;
+            },
+            Operand::Label(label) if position == 0 && ABSOLUTE_JUMPS.contains(&opcode) => {
+                let target = *labels.get(&label).ok_or_else(|| error(AsmErrorKind::UndefinedLabel))?;
+                if target > 0b0111_1111 {
+                    return Err(error(AsmErrorKind::JumpOutOfRange));
+                }
+                bytes[1] = 0b1000_0000 | target as u8;
+            },
+            Operand::Label(_) => return Err(error(AsmErrorKind::InvalidLabelUse)),
+        }
+    }
+
+    let mut words = vec![bytes];
+    words.extend(extra);
+    Ok(words)
+}
+
+/// The CPU measures the jump distance from the instruction after the jump.
+fn relative_jump(from: usize, target: usize) -> Option<(u8, u8)> {
+    let (distance, sign) = if target > from {
+        (target - from - 1, 1)
+    } else {
+        (from + 1 - target, 0)
+    };
+
+    if distance > 0b0111_1111 {
+        None
+    } else {
+        Some((distance as u8, sign))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_labels() {
+        let program = parse_program("
+            ; count r1 up to 8
+            set 0 1
+            loop: add 1 0r 1r   # r1 += r0
+            let 3 1r 8
+            jpt loop 3r
+            jpf end 2r
+            hlt
+            end:                ; skip the hlt
+            set 2 9
+        ");
+
+        assert_eq!(program, Ok(vec![
+            [0x03, 128, 129, 0],
+            [0x20, 129, 0x10, 0x11],
+            [0x32, 131, 0x11, 136],
+            [0x10, 131, 0x13, 128],
+            [0x11, 129, 0x12, 129],
+            [0x00, 0, 0, 0],
+            [0x03, 130, 137, 0],
+        ]));
+    }
+
+    #[test]
+    fn assemble_with_symbols() {
+        let bytes = assemble("
+            set 0 1
+            loop: add 1 0r 1r
+            jpt loop 1
+            end: hlt
+        ").unwrap();
+        let object = Object::from_bytes(&bytes).unwrap();
+
+        assert_eq!(object.entry, 0);
+        assert_eq!(object.rom.len(), 4);
+        assert_eq!(object.symbols, vec![(String::from("loop"), 1), (String::from("end"), 3)]);
+    }
+
+    #[test]
+    fn call_labels() {
+        let program = parse_program("
+            cal double
+            hlt
+            double: add 0 0r 0r
+            ret
+        ");
+
+        assert_eq!(program, Ok(vec![
+            [0x12, 129, 0, 129],
+            [0x00, 0, 0, 0],
+            [0x20, 128, 0x10, 0x10],
+            [0x13, 0, 0, 0],
+        ]));
+    }
+
+    #[test]
+    fn absolute_labels() {
+        let program = parse_program("
+            start: jmp end
+            hlt
+            end: jpa start
+        ");
+
+        assert_eq!(program, Ok(vec![
+            [0x14, 129, 0, 129],
+            [0x00, 0, 0, 0],
+            [0x15, 128, 0, 0],
+        ]));
+    }
+
+    #[test]
+    fn wide_constants() {
+        let program = parse_program("
+            set 0 200
+            set 1 0x100000002
+            set 2 -2
+            loop: jpt loop 0r
+        ");
+
+        assert_eq!(program, Ok(vec![
+            [0x07, 128, 129, 0],
+            [0, 0, 0, 200],
+            [0x07, 129, 130, 0],
+            [0, 0, 0, 1],
+            [0, 0, 0, 2],
+            [0x07, 130, 130, 0],
+            [0xFF, 0xFF, 0xFF, 0xFF],
+            [0xFF, 0xFF, 0xFF, 0xFE],
+            [0x10, 129, 0x10, 128],
+        ]));
+    }
+
+    fn errors(source: &str) -> Vec<(usize, usize, String, AsmErrorKind)> {
+        parse_program(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|error| (error.line, error.column, error.token, error.kind))
+            .collect()
+    }
+
+    #[test]
+    fn label_errors() {
+        let errors = errors("
+start: set 0 1
+loop: add 1 0r 1r
+loop: jpt nowhere 3r
+jpt start 3r
+add loop 1 2
+");
+
+        assert_eq!(errors, vec![
+            (4, 1, String::from("loop:"), AsmErrorKind::DuplicateLabel),
+            (4, 11, String::from("nowhere"), AsmErrorKind::UndefinedLabel),
+            (6, 5, String::from("loop"), AsmErrorKind::InvalidLabelUse),
+        ]);
+    }
+
+    #[test]
+    fn set_labels() {
+        let source = format!("set 5 start\nset 6 far\njpa 6r\nstart: {}far: hlt", "hlt\n".repeat(126));
+        let program = parse_program(&source).unwrap();
+
+        assert_eq!(&program[..4], &[
+            [0x03, 133, 132, 0],
+            [0x07, 134, 129, 0],
+            [0, 0, 0, 130],
+            [0x15, 0x16, 0, 0],
+        ]);
+        assert_eq!(program.len(), 131);
+        assert_eq!(errors("set 0 nowhere"), vec![(1, 7, String::from("nowhere"), AsmErrorKind::UndefinedLabel)]);
+    }
+
+    #[test]
+    fn code_base_labels() {
+        let object = assemble_object_at("
+            start: jmp end
+            set 0 end
+            end: jpa start
+        ", 16).unwrap();
+
+        assert_eq!(object.rom, vec![
+            [0x14, 129, 0, 129],
+            [0x03, 128, 146, 0],
+            [0x15, 144, 0, 0],
+        ]);
+        assert_eq!(object.symbols, vec![(String::from("start"), 0), (String::from("end"), 2)]);
+    }
+
+    #[test]
+    fn far_jumps() {
+        let source = format!("start: hlt\n{}jpt start 3r", "hlt\n".repeat(127));
+        assert_eq!(errors(&source), vec![
+            (129, 5, String::from("start"), AsmErrorKind::JumpOutOfRange),
+        ]);
+    }
+
+    #[test]
+    fn report_every_error() {
+        let errors = errors("
+set 0 1
+sot 0 1
+add 0 1 2 3
+add 0 300 1 ; too big
+hlt
+");
+
+        assert_eq!(errors, vec![
+            (3, 1, String::from("sot"), AsmErrorKind::Syntax { expected: String::from("an opcode") }),
+            (4, 11, String::from("3"), AsmErrorKind::Syntax { expected: String::from("end of line") }),
+            (5, 7, String::from("300"), AsmErrorKind::ValueOutOfRange { max: 127 }),
+        ]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::convert::TryFrom;
+use std::io;
+use std::ops::Range;
+
+use crate::Word;
+
+/// A peripheral that answers `ldr` and `str` on a range of addresses.
+///
+/// Offsets are relative to the address the device is attached at.
+pub trait Device {
+    /// A short name for listings, like `console`.
+    fn name(&self) -> &str;
+    /// How many consecutive addresses the device answers to.
+    fn words(&self) -> usize;
+    fn read(&mut self, offset: usize) -> io::Result<Word>;
+    fn write(&mut self, offset: usize, value: Word) -> io::Result<()>;
+    /// Called once after every instruction the machine executes, returning
+    /// an interrupt to raise, if any.
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// Routes memory accesses to the devices mapped over them.
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+struct Mapping {
+    base: usize,
+    /// One past the last address, checked when the device is attached.
+    end: usize,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    fn range(&self) -> Range<usize> {
+        self.base..self.end
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BusError {
+    /// The device's addresses overlap a device that's already attached.
+    Overlap { base: usize, words: usize, existing: String },
+    /// The device's addresses run past the largest address.
+    OutOfRange { base: usize, words: usize },
+}
+
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::Overlap { base, words, existing } => {
+                write!(f, "{} words at address {} overlap the {} device", words, base, existing)
+            },
+            BusError::OutOfRange { base, words } => {
+                write!(f, "{} words at address {} run past the end of the address space", words, base)
+            },
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl Bus {
+    pub fn new() -> Self { Default::default() }
+
+    /// Maps `device` over the addresses starting at `base`. They no longer
+    /// reach memory, even if they're inside it.
+    pub fn attach(&mut self, base: usize, device: Box<dyn Device>) -> Result<(), BusError> {
+        let words = device.words();
+        let end = base.checked_add(words).ok_or(BusError::OutOfRange { base, words })?;
+        let range = base..end;
+        if let Some(existing) = self.mappings.iter().find(|mapping| {
+            let other = mapping.range();
+            range.start < other.end && other.start < range.end
+        }) {
+            return Err(BusError::Overlap { base, words, existing: existing.device.name().to_string() });
+        }
+
+        self.mappings.push(Mapping { base, end, device });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// The address range and name of each attached device.
+    pub fn mappings(&self) -> impl Iterator<Item = (Range<usize>, &str)> {
+        self.mappings.iter().map(|mapping| (mapping.range(), mapping.device.name()))
+    }
+
+    /// Reads from the device mapped at `address`, or `None` if there isn't one.
+    pub fn read(&mut self, address: Word) -> Option<io::Result<Word>> {
+        self.find(address).map(|(device, offset)| device.read(offset))
+    }
+
+    /// Writes to the device mapped at `address`, or `None` if there isn't one.
+    pub fn write(&mut self, address: Word, value: Word) -> Option<io::Result<()>> {
+        self.find(address).map(|(device, offset)| device.write(offset, value))
+    }
+
+    /// Ticks every device, returning the interrupts they raised with bit n
+    /// set for interrupt n. Interrupts past the last vector are dropped.
+    pub fn tick(&mut self) -> u16 {
+        let mut raised = 0;
+        for mapping in &mut self.mappings {
+            if let Some(interrupt) = mapping.device.tick() {
+                raised |= 1u16.checked_shl(interrupt as u32).unwrap_or(0);
+            }
+        }
+        raised
+    }
+
+    fn find(&mut self, address: Word) -> Option<(&mut (dyn Device + 'static), usize)> {
+        let address = usize::try_from(address).ok()?;
+        self.mappings.iter_mut()
+            .find(|mapping| mapping.range().contains(&address))
+            .map(|mapping| (mapping.device.as_mut(), address - mapping.base))
+    }
+}
+
+impl std::fmt::Debug for Bus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.mappings())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Random, Timer};
+
+    #[test]
+    fn attach_and_dispatch() {
+        let mut bus = Bus::new();
+        bus.attach(100, Box::new(Timer::new())).unwrap();
+        bus.attach(110, Box::new(Random::new(1))).unwrap();
+        assert_eq!(
+            bus.attach(100, Box::new(Timer::new())),
+            Err(BusError::Overlap { base: 100, words: Timer::new().words(), existing: String::from("timer") }),
+        );
+
+        assert_eq!(
+            bus.attach(usize::MAX, Box::new(Timer::new())),
+            Err(BusError::OutOfRange { base: usize::MAX, words: Timer::new().words() }),
+        );
+
+        assert_eq!(bus.tick(), 0);
+        bus.tick();
+        assert_eq!(bus.read(100).unwrap().unwrap(), 2);
+        assert!(bus.write(100, 0).unwrap().is_ok());
+        assert_eq!(bus.read(100).unwrap().unwrap(), 0);
+        assert!(bus.read(99).is_none());
+        assert!(bus.read(Word::MAX).is_none());
+        assert_eq!(bus.mappings().map(|(_, name)| name).collect::<Vec<_>>(), vec!["timer", "random"]);
+    }
+}
@@ -0,0 +1,175 @@
+use gorp_asm::object::Profile;
+
+use crate::Cpu;
+use crate::io::{IoDevice, StdIo};
+
+/// The shape of a machine: how much memory and how many registers it has,
+/// and how big a program it will load and run.
+///
+/// ```
+/// let cpu = gorp_cpu::Cpu::builder().memory_words(256).registers(4).max_cycles(1000).build();
+/// assert_eq!(cpu.memory().len(), 256);
+/// ```
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CpuConfig {
+    pub memory_words: usize,
+    pub registers: usize,
+    /// Words at the top of memory the stack may grow into.
+    pub stack_words: usize,
+    pub max_rom: Option<usize>,
+    pub max_cycles: Option<u64>,
+    /// Where to load code in memory for von Neumann mode, where instructions
+    /// are fetched from memory and can be read and written like data.
+    /// `None` keeps code in a separate ROM.
+    pub code_base: Option<usize>,
+    /// Where the interrupt vector table starts in memory.
+    pub vector_table: usize,
+    /// How many steps `Cpu::step_back` can undo. 0 keeps no history.
+    pub history_limit: usize,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            memory_words: 65536,
+            registers: 16,
+            stack_words: crate::STACK_WORDS,
+            max_rom: None,
+            max_cycles: None,
+            code_base: None,
+            vector_table: 0,
+            history_limit: 0,
+        }
+    }
+}
+
+impl CpuConfig {
+    /// A small embedded-style machine.
+    pub fn tiny() -> Self {
+        Self {
+            memory_words: 256,
+            registers: 8,
+            stack_words: 32,
+            max_rom: Some(256),
+            max_cycles: None,
+            code_base: None,
+            vector_table: 0,
+            history_limit: 0,
+        }
+    }
+
+    /// A machine with a million words of memory and a deep stack.
+    pub fn large() -> Self {
+        Self {
+            memory_words: 1 << 20,
+            stack_words: 1 << 16,
+            ..Default::default()
+        }
+    }
+
+    pub fn memory_words(mut self, words: usize) -> Self {
+        self.memory_words = words;
+        self
+    }
+
+    pub fn registers(mut self, count: usize) -> Self {
+        self.registers = count;
+        self
+    }
+
+    pub fn stack_words(mut self, words: usize) -> Self {
+        self.stack_words = words;
+        self
+    }
+
+    pub fn max_rom(mut self, instructions: usize) -> Self {
+        self.max_rom = Some(instructions);
+        self
+    }
+
+    pub fn max_cycles(mut self, cycles: u64) -> Self {
+        self.max_cycles = Some(cycles);
+        self
+    }
+
+    pub fn code_base(mut self, address: usize) -> Self {
+        self.code_base = Some(address);
+        self
+    }
+
+    pub fn vector_table(mut self, address: usize) -> Self {
+        self.vector_table = address;
+        self
+    }
+
+    pub fn history_limit(mut self, steps: usize) -> Self {
+        self.history_limit = steps;
+        self
+    }
+
+    /// Overrides every setting that `profile` gives a non-zero value.
+    pub fn with_profile(mut self, profile: &Profile) -> Self {
+        if profile.registers != 0 {
+            self.registers = profile.registers as usize;
+        }
+        if profile.memory_words != 0 {
+            self.memory_words = profile.memory_words as usize;
+        }
+        if profile.stack_words != 0 {
+            self.stack_words = profile.stack_words as usize;
+        }
+        if profile.max_rom != 0 {
+            self.max_rom = Some(profile.max_rom as usize);
+        }
+        if profile.max_cycles != 0 {
+            self.max_cycles = Some(profile.max_cycles);
+        }
+        if let Some(base) = profile.code_base {
+            self.code_base = Some(base as usize);
+        }
+        if profile.vector_table != 0 {
+            self.vector_table = profile.vector_table as usize;
+        }
+        self
+    }
+
+    /// The profile to store in an object file, with unlimited settings left at 0.
+    /// Settings too big for the object header are also left at 0.
+    pub fn profile(&self) -> Profile {
+        use std::convert::TryFrom;
+
+        Profile {
+            registers: u16::try_from(self.registers).unwrap_or(0),
+            memory_words: u32::try_from(self.memory_words).unwrap_or(0),
+            stack_words: u32::try_from(self.stack_words).unwrap_or(0),
+            max_rom: self.max_rom.and_then(|max| u32::try_from(max).ok()).unwrap_or(0),
+            max_cycles: self.max_cycles.unwrap_or(0),
+            code_base: self.code_base.and_then(|base| u32::try_from(base).ok()),
+            vector_table: u32::try_from(self.vector_table).unwrap_or(0),
+        }
+    }
+
+    pub fn build(self) -> Cpu {
+        self.build_with_io(StdIo::new())
+    }
+
+    pub fn build_with_io<D: IoDevice>(self, io: D) -> Cpu<D> {
+        Cpu::with_config(self, io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_round_trip() {
+        let config = CpuConfig::default().memory_words(256).registers(4).max_cycles(1000).code_base(0).vector_table(8);
+        let profile = config.profile();
+        assert_eq!(profile.memory_words, 256);
+        assert_eq!(profile.code_base, Some(0));
+        assert_eq!(profile.max_rom, 0);
+        assert_eq!(CpuConfig::default().with_profile(&profile), config);
+        assert_eq!(CpuConfig::default().with_profile(&Profile::default()), CpuConfig::default());
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Stdin, Stdout, Write};
+use std::path::Path;
+
+use crate::Word;
+use crate::bus::Device;
+
+/// A character terminal at a single address. Reads return the next byte of
+/// input, or all ones at the end of input, and writes print the low byte.
+#[derive(Debug)]
+pub struct Console<R: Read = Stdin, W: Write = Stdout> {
+    pub input: R,
+    pub output: W,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::with(io::stdin(), io::stdout())
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Read, W: Write> Console<R, W> {
+    pub fn with(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+}
+
+impl<R: Read, W: Write> Device for Console<R, W> {
+    fn name(&self) -> &str { "console" }
+
+    fn words(&self) -> usize { 1 }
+
+    fn read(&mut self, _offset: usize) -> io::Result<Word> {
+        let mut byte = [0];
+        match self.input.read(&mut byte)? {
+            0 => Ok(Word::MAX),
+            _ => Ok(byte[0] as Word),
+        }
+    }
+
+    fn write(&mut self, _offset: usize, value: Word) -> io::Result<()> {
+        self.output.write_all(&[value as u8])?;
+        self.output.flush()
+    }
+}
+
+/// Counts the instructions executed since it was attached or last reset.
+///
+/// The first address holds the count and the second the period. While the
+/// period isn't zero the timer raises its interrupt every period ticks.
+#[derive(Debug, Default)]
+pub struct Timer {
+    ticks: Word,
+    period: Word,
+    interrupt: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self { Default::default() }
+
+    /// A timer that raises `interrupt` every `period` instructions.
+    pub fn every(period: Word, interrupt: u8) -> Self {
+        Self { ticks: 0, period, interrupt }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str { "timer" }
+
+    fn words(&self) -> usize { 2 }
+
+    fn read(&mut self, offset: usize) -> io::Result<Word> {
+        Ok(if offset == 0 { self.ticks } else { self.period })
+    }
+
+    fn write(&mut self, offset: usize, value: Word) -> io::Result<()> {
+        if offset == 0 {
+            self.ticks = value;
+        } else {
+            self.period = value;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Option<u8> {
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.period != 0 && self.ticks.is_multiple_of(self.period) {
+            Some(self.interrupt)
+        } else {
+            None
+        }
+    }
+}
+
+/// A xorshift random number generator. Each read returns a new number, and
+/// writing reseeds it.
+#[derive(Debug)]
+pub struct Random {
+    state: Word,
+}
+
+impl Random {
+    pub fn new(seed: Word) -> Self {
+        let mut random = Self { state: 0 };
+        random.seed(seed);
+        random
+    }
+
+    fn seed(&mut self, seed: Word) {
+        // Xorshift never leaves zero
+        self.state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+    }
+}
+
+impl Device for Random {
+    fn name(&self) -> &str { "random" }
+
+    fn words(&self) -> usize { 1 }
+
+    fn read(&mut self, _offset: usize) -> io::Result<Word> {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        Ok(self.state)
+    }
+
+    fn write(&mut self, _offset: usize, value: Word) -> io::Result<()> {
+        self.seed(value);
+        Ok(())
+    }
+}
+
+/// A `width` by `height` grid of pixels, one word each, stored row by row.
+/// Writing to the word after the last pixel draws the frame as text.
+#[derive(Debug)]
+pub struct Framebuffer<W: Write = Stdout> {
+    width: usize,
+    height: usize,
+    pixels: Vec<Word>,
+    output: W,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::with_output(width, height, io::stdout())
+    }
+}
+
+impl<W: Write> Framebuffer<W> {
+    pub fn with_output(width: usize, height: usize, output: W) -> Self {
+        Self { width, height, pixels: vec![0; width * height], output }
+    }
+
+    pub fn pixels(&self) -> &[Word] {
+        &self.pixels
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Pixels holding a printable ASCII character are drawn as that
+    /// character, other set pixels as `#` and clear ones as spaces.
+    pub fn render(&self) -> String {
+        let mut frame = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width.max(1)) {
+            for pixel in row {
+                frame.push(match *pixel {
+                    0 => ' ',
+                    pixel if (0x20..0x7F).contains(&pixel) => pixel as u8 as char,
+                    _ => '#',
+                });
+            }
+            frame.push('\n');
+        }
+        frame
+    }
+}
+
+impl<W: Write> Device for Framebuffer<W> {
+    fn name(&self) -> &str { "framebuffer" }
+
+    fn words(&self) -> usize {
+        self.pixels.len() + 1
+    }
+
+    fn read(&mut self, offset: usize) -> io::Result<Word> {
+        Ok(self.pixels.get(offset).copied().unwrap_or(0))
+    }
+
+    fn write(&mut self, offset: usize, value: Word) -> io::Result<()> {
+        match self.pixels.get_mut(offset) {
+            Some(pixel) => *pixel = value,
+            None => {
+                let frame = self.render();
+                self.output.write_all(frame.as_bytes())?;
+                self.output.flush()?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The number of words in each block of a `BlockStorage` device.
+pub const BLOCK_WORDS: usize = 512;
+
+/// A disk of `BLOCK_WORDS` word blocks, stored as 8 byte little-endian words.
+///
+/// The first address selects a block, and the `BLOCK_WORDS` after it read and
+/// write that block's words. Words past the end of the storage read as zero.
+#[derive(Debug)]
+pub struct BlockStorage<S: Read + Write + Seek = File> {
+    storage: S,
+    block: Word,
+}
+
+impl BlockStorage {
+    /// Opens or creates a disk image at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<S: Read + Write + Seek> BlockStorage<S> {
+    pub fn new(storage: S) -> Self {
+        Self { storage, block: 0 }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Seeks to word `offset` of the selected block.
+    fn seek(&mut self, offset: usize) -> io::Result<()> {
+        let word = self.block
+            .checked_mul(BLOCK_WORDS as Word)
+            .and_then(|start| start.checked_add(offset as Word))
+            .and_then(|word| word.checked_mul(std::mem::size_of::<Word>() as Word))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("block {} out of range", self.block)))?;
+        self.storage.seek(SeekFrom::Start(word))?;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Seek> Device for BlockStorage<S> {
+    fn name(&self) -> &str { "storage" }
+
+    fn words(&self) -> usize {
+        BLOCK_WORDS + 1
+    }
+
+    fn read(&mut self, offset: usize) -> io::Result<Word> {
+        if offset == 0 {
+            return Ok(self.block);
+        }
+
+        self.seek(offset - 1)?;
+        let mut buffer = [0; std::mem::size_of::<Word>()];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.storage.read(&mut buffer[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        Ok(Word::from_le_bytes(buffer))
+    }
+
+    fn write(&mut self, offset: usize, value: Word) -> io::Result<()> {
+        if offset == 0 {
+            self.block = value;
+            return Ok(());
+        }
+
+        self.seek(offset - 1)?;
+        self.storage.write_all(&value.to_le_bytes())?;
+        self.storage.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn console() {
+        let mut console = Console::with(&b"hi"[..], Vec::new());
+        assert_eq!(console.read(0).unwrap(), 'h' as Word);
+        assert_eq!(console.read(0).unwrap(), 'i' as Word);
+        assert_eq!(console.read(0).unwrap(), Word::MAX);
+
+        console.write(0, 'o' as Word).unwrap();
+        console.write(0, 'k' as Word).unwrap();
+        assert_eq!(console.output, b"ok");
+    }
+
+    #[test]
+    fn timer() {
+        let mut timer = Timer::every(2, 3);
+        assert_eq!(timer.tick(), None);
+        assert_eq!(timer.tick(), Some(3));
+        assert_eq!(timer.read(0).unwrap(), 2);
+
+        timer.write(1, 0).unwrap();
+        assert_eq!((timer.tick(), timer.tick()), (None, None));
+    }
+
+    #[test]
+    fn random() {
+        let mut first = Random::new(7);
+        let mut second = Random::new(7);
+        let numbers: Vec<Word> = (0..4).map(|_| first.read(0).unwrap()).collect();
+        assert_eq!(numbers, (0..4).map(|_| second.read(0).unwrap()).collect::<Vec<_>>());
+        assert!(numbers.windows(2).all(|pair| pair[0] != pair[1]));
+
+        first.write(0, 0).unwrap();
+        assert_ne!(first.read(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let mut framebuffer = Framebuffer::with_output(3, 2, Vec::new());
+        assert_eq!(framebuffer.words(), 7);
+        framebuffer.write(0, 'a' as Word).unwrap();
+        framebuffer.write(4, 1).unwrap();
+        assert_eq!(framebuffer.read(4).unwrap(), 1);
+
+        framebuffer.write(6, 0).unwrap();
+        assert_eq!(framebuffer.output(), b"a  \n # \n");
+    }
+
+    #[test]
+    fn block_storage() {
+        let mut storage = BlockStorage::new(Cursor::new(Vec::new()));
+        assert_eq!(storage.read(1).unwrap(), 0);
+
+        storage.write(0, 1).unwrap();
+        storage.write(3, 42).unwrap();
+        assert_eq!(storage.read(0).unwrap(), 1);
+        assert_eq!(storage.read(3).unwrap(), 42);
+
+        storage.write(0, 0).unwrap();
+        assert_eq!(storage.read(3).unwrap(), 0);
+
+        let bytes = storage.into_inner().into_inner();
+        assert_eq!(bytes.len(), (BLOCK_WORDS + 3) * 8);
+        assert_eq!(bytes[(BLOCK_WORDS + 2) * 8], 42);
+    }
+}
@@ -0,0 +1,101 @@
+use gorp_asm::AsmError;
+use gorp_asm::object::ObjectError;
+use crate::Word;
+use crate::instruction::Instruction;
+
+/// The reason the machine stopped executing an instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    UnknownOpcode(u8),
+    MemoryFault(Word),
+    InvalidRegister(Word),
+    DivideByZero,
+    PcOutOfBounds,
+    InvalidMode(u8),
+    IoError(String),
+    StackOverflow,
+    StackUnderflow,
+    /// The machine ran for its configured maximum number of cycles.
+    CycleLimit(u64),
+    /// An interrupt was raised with no handler in the vector table.
+    UnhandledInterrupt(Word),
+    /// A `wfi` would wait forever.
+    WaitingWithInterruptsDisabled,
+    /// A `wfi` would wait forever, since no device can raise an interrupt.
+    WaitingWithoutDevices,
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trap::UnknownOpcode(opcode) => write!(f, "unknown opcode 0x{:02x}", opcode),
+            Trap::MemoryFault(address) => write!(f, "memory fault at address {}", address),
+            Trap::InvalidRegister(register) => write!(f, "invalid register {}", register),
+            Trap::DivideByZero => write!(f, "divide by zero"),
+            Trap::PcOutOfBounds => write!(f, "program counter out of bounds"),
+            Trap::InvalidMode(mode) => write!(f, "invalid addressing mode {:04b}", mode),
+            Trap::IoError(message) => write!(f, "i/o error: {}", message),
+            Trap::StackOverflow => write!(f, "stack overflow"),
+            Trap::StackUnderflow => write!(f, "stack underflow"),
+            Trap::CycleLimit(limit) => write!(f, "cycle limit of {} reached", limit),
+            Trap::UnhandledInterrupt(interrupt) => write!(f, "no handler for interrupt {}", interrupt),
+            Trap::WaitingWithInterruptsDisabled => write!(f, "waiting for an interrupt with interrupts disabled"),
+            Trap::WaitingWithoutDevices => write!(f, "waiting for an interrupt with no devices attached"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CpuError {
+    /// An instruction trapped while executing.
+    Trap {
+        pc: usize,
+        instruction: Instruction,
+        trap: Trap,
+    },
+    /// A program could not be read.
+    Io(std::io::Error),
+    /// A program could not be assembled.
+    Asm(Vec<AsmError>),
+    /// An object file or ROM image was malformed.
+    Object(ObjectError),
+}
+
+impl CpuError {
+    pub fn trap(&self) -> Option<&Trap> {
+        match self {
+            CpuError::Trap { trap, .. } => Some(trap),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::Trap { pc, instruction, trap } => {
+                write!(f, "{} at pc {} ({})", trap, pc, instruction.as_assembly())
+            },
+            CpuError::Io(error) => write!(f, "{}", error),
+            CpuError::Asm(errors) => {
+                let messages: Vec<String> = errors.iter().map(AsmError::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+            CpuError::Object(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+impl From<ObjectError> for CpuError {
+    fn from(error: ObjectError) -> Self {
+        CpuError::Object(error)
+    }
+}
+
+impl From<std::io::Error> for CpuError {
+    fn from(error: std::io::Error) -> Self {
+        CpuError::Io(error)
+    }
+}
@@ -0,0 +1,60 @@
+use crate::Word;
+
+/// The status flags set by arithmetic and comparison instructions.
+///
+/// As a mask, used by the flag jumps, zero is bit 0, carry bit 1,
+/// overflow bit 2 and negative bit 3.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Flags {
+    pub zero: bool,
+    /// An unsigned add or multiply didn't fit, or a subtraction borrowed.
+    pub carry: bool,
+    /// The result doesn't fit when the operands are read as signed.
+    pub overflow: bool,
+    pub negative: bool,
+}
+
+pub const ZERO: u8 = 0b0001;
+pub const CARRY: u8 = 0b0010;
+pub const OVERFLOW: u8 = 0b0100;
+pub const NEGATIVE: u8 = 0b1000;
+
+impl Flags {
+    /// The zero and negative flags for `result`, with carry and overflow clear.
+    pub fn of(result: Word) -> Self {
+        Self {
+            zero: result == 0,
+            negative: (result as i64) < 0,
+            ..Default::default()
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        let mut bits = 0;
+        for (flag, bit) in [(self.zero, ZERO), (self.carry, CARRY), (self.overflow, OVERFLOW), (self.negative, NEGATIVE)] {
+            if flag {
+                bits |= bit;
+            }
+        }
+        bits
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self {
+            zero: bits & ZERO != 0,
+            carry: bits & CARRY != 0,
+            overflow: bits & OVERFLOW != 0,
+            negative: bits & NEGATIVE != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let letters = [(self.zero, 'Z'), (self.carry, 'C'), (self.overflow, 'V'), (self.negative, 'N')];
+        for (flag, letter) in letters.iter() {
+            write!(f, "{}", if *flag { *letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+use crate::{State, Word};
+use crate::flags::Flags;
+
+/// The machine state before one step, and the old value of everything the
+/// step overwrote.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub pc: usize,
+    pub sp: usize,
+    pub flags: Flags,
+    pub state: State,
+    pub cycles: u64,
+    pub interrupts_enabled: bool,
+    pub pending_interrupts: u16,
+    /// Old values in the order they were overwritten.
+    pub changes: Vec<Change>,
+}
+
+/// The value a register or memory cell held before a step wrote to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Register(usize, Word),
+    Memory(usize, Word),
+}
+
+/// An undo log of the most recent steps, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    limit: usize,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl History {
+    /// Keeps at most `limit` steps. A limit of 0 keeps no history.
+    pub fn new(limit: usize) -> Self {
+        Self { limit, snapshots: VecDeque::new() }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Changes the limit, dropping the oldest steps if there are too many.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.snapshots.len() > limit {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Adds the latest step, dropping the oldest one when full.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.limit {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Removes the latest step.
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        self.snapshots.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
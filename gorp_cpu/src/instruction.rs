@@ -1,27 +1,64 @@
+/// # Words
+/// Registers and memory cells hold 64-bit words on every host. Signed
+/// instructions treat them as two's complement.
+///
 /// # Instruction Format
 /// [  00000000  |  0000_0000  |  0000_0000  |  0000_0000  ]
 /// [  opcode    |  mode_src1  |  mode_src2  |  mode_dest  ]  
 ///
 /// # Addressing
-/// 0000 - immediate  
-/// 0001 - register  
-/// 001x - ?  
-/// 01xx - offset  
-/// 1xxx - immediate  
+/// 0000 - immediate (4 bits, written `Ns`)  
+/// 0001 - register (written `Nr`)  
+/// 001x - invalid  
+/// 01xx - pc-relative offset (6 bits signed, -32 to 31, written `No`)  
+/// 1xxx - immediate (7 bits, written `N` or `Ni`)  
+///
+/// An offset parameter evaluates to the address of the current instruction
+/// plus the offset, so `ldr 0 -2o` loads the word two addresses before this
+/// instruction. That's most useful in von Neumann mode, for constants kept
+/// next to the code that uses them.
+///
+/// `ldr` and `str` add their last operand to the memory address, for
+/// base+offset addressing into arrays and structs. `ldr 0 3r 2` loads
+/// memory[r3 + 2] into r0, and `str 3r 1 2` stores r1 at memory[r3 + 2].
+///
+/// Words that aren't a valid instruction are written `.word 0xXXXXXXXX`.
+///
+/// In von Neumann mode each instruction is stored in the low 32 bits of a
+/// memory word, so `ldr` and `str` can read and rewrite code. Absolute jump
+/// targets are memory addresses, so labels are assembled relative to the
+/// code base and an object only loads at the code base it was built for.
 ///
 /// # Instructions
 ///
 /// 00 - halt
 ///
 /// ## Data Operations
-/// 01 - load reg mem
-/// 02 - stor mem reg
+/// 01 - load reg mem offset
+/// 02 - stor mem reg offset
 /// 03 - set reg val
 /// 04 - copy destreg srcreg
+/// 05 - psh val
+/// 06 - pop reg
+/// 07 - setw reg count (sets reg from the next count ROM words, most significant first, and skips them)
+///
+/// The stack grows down from the end of memory, `sp` pointing at the top
+/// word. It holds at most `STACK_WORDS` words before trapping.
 ///
 /// ## Jumps
 /// 10 - jmpt pos test sign 
 /// 11 - jmpf pos test sign
+/// 12 - cal pos _ sign (pushes pc, then jumps like jmpt)
+/// 13 - ret (pops pc)
+/// 14 - jmp pos _ sign
+/// 15 - jpa addr (jumps to addr, e.g. `jpa 3r` jumps to the address in r3)
+/// 16 - jpi addr (jumps to the address stored at memory[addr], for jump tables)
+/// 17 - jfs pos mask sign (jumps if any flag in mask is set)
+/// 18 - jfc pos mask sign (jumps if every flag in mask is clear)
+///
+/// Flag masks are zero 1, carry 2, overflow 4 and negative 8. Math and
+/// comparison instructions set the flags, comparisons as if subtracting y
+/// from x. Math wraps around rather than trapping on overflow.
 ///
 /// ## Math
 /// 20 - add dest x y 
@@ -29,6 +66,8 @@
 /// 22 - mul dest x y
 /// 23 - div dest x y
 /// 24 - mod dest x y
+/// 25 - sdv dest x y (signed)
+/// 26 - smd dest x y (signed, takes the sign of x)
 ///
 /// ## Comparison
 /// 30 - eq dest x y
@@ -37,10 +76,40 @@
 /// 33 - le dest x y
 /// 34 - gt dest x y
 /// 35 - ge dest x y
+/// 36 - slt dest x y (signed)
+/// 37 - sle dest x y (signed)
+/// 38 - sgt dest x y (signed)
+/// 39 - sge dest x y (signed)
+///
+/// ## Bitwise
+/// 40 - and dest x y
+/// 41 - orr dest x y
+/// 42 - xor dest x y
+/// 43 - not dest x
+/// 44 - shl dest x amount
+/// 45 - shr dest x amount (logical)
+/// 46 - sar dest x amount (arithmetic, keeps the sign bit)
 ///
 /// ## I/O
 /// 50 - sti dest
 /// 51 - sto val
+///
+/// Devices can also be mapped over memory addresses with `Cpu::attach`, so
+/// `ldr` and `str` on those addresses talk to the device instead.
+///
+/// ## Interrupts
+/// 60 - int num (takes interrupt num now, even if interrupts are disabled)
+/// 61 - iret (returns from a handler)
+/// 62 - eni (enables interrupts)
+/// 63 - dsi (disables interrupts)
+/// 64 - wfi (waits for an interrupt, then carries on after the wfi)
+///
+/// The vector table holds the handler address for each of the 16 interrupts,
+/// starting at `CpuConfig::vector_table`. A zero entry means no handler.
+/// Raised interrupts are taken after an instruction while interrupts are
+/// enabled, lowest number first. Taking one pushes the return address, the
+/// flags and interrupt enable, and then every register in order, and
+/// disables interrupts. `iret` pops them all again. Interrupts start disabled.
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
@@ -72,9 +141,11 @@ impl From<u32> for Instruction {
     }
 }
 
-impl From<&str> for Instruction {
-    fn from(value: &str) -> Self {
-        Instruction::from(gorp_asm::parse_instruction(value))
+impl std::convert::TryFrom<&str> for Instruction {
+    type Error = gorp_asm::AsmError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        gorp_asm::parse_instruction(value).map(Instruction::from)
     }
 }
 
@@ -99,55 +170,116 @@ impl Instruction {
         <(u8, u8, u8, u8)>::from(self)
     }
 
-    pub fn as_assembly(&self) -> String {
-        let instruction = match self.opcode {
+    pub fn mnemonic(&self) -> Option<&'static str> {
+        let mnemonic = match self.opcode {
             0x00 => "hlt",
             0x01 => "ldr",
             0x02 => "str",
             0x03 => "set",
             0x04 => "cpy",
+            0x05 => "psh",
+            0x06 => "pop",
+            0x07 => "setw",
             0x10 => "jpt",
             0x11 => "jpf",
+            0x12 => "cal",
+            0x13 => "ret",
+            0x14 => "jmp",
+            0x15 => "jpa",
+            0x16 => "jpi",
+            0x17 => "jfs",
+            0x18 => "jfc",
             0x20 => "add",
             0x21 => "sub",
             0x22 => "mul",
             0x23 => "div",
             0x24 => "mod",
+            0x25 => "sdv",
+            0x26 => "smd",
             0x30 => "eql",
             0x31 => "neq",
             0x32 => "let",
             0x33 => "leq",
             0x34 => "grt",
             0x35 => "geq",
+            0x36 => "slt",
+            0x37 => "sle",
+            0x38 => "sgt",
+            0x39 => "sge",
+            0x40 => "and",
+            0x41 => "orr",
+            0x42 => "xor",
+            0x43 => "not",
+            0x44 => "shl",
+            0x45 => "shr",
+            0x46 => "sar",
             0x50 => "sti",
             0x51 => "sto",
-            _    => "invalid",
+            0x60 => "int",
+            0x61 => "iret",
+            0x62 => "eni",
+            0x63 => "dsi",
+            0x64 => "wfi",
+            _    => return None,
         };
 
-        let dest = Self::parameter_as_str(self.dest);
-        let op1 = Self::parameter_as_str(self.op1);
-        let op2 = Self::parameter_as_str(self.op2);
-        
+        Some(mnemonic)
+    }
+
+    /// The number of ROM words after this instruction that hold data rather than code.
+    pub fn extension_words(&self) -> usize {
+        match (self.opcode, self.op1 >> 4) {
+            (0x07, mode) if mode >= 0b1000 => (self.op1 & 0b0111_1111) as usize,
+            (0x07, 0) => self.op1 as usize,
+            _ => 0,
+        }
+    }
 
-        format!("{} {} {} {}", instruction, dest, op1, op2)
+    /// Returns assembly that `gorp_asm` assembles back into this exact instruction.
+    ///
+    /// Trailing operands that are all zero bits are left off, since the
+    /// assembler fills missing operands with zeros.
+    pub fn as_assembly(&self) -> String {
+        let parameters = [self.dest, self.op1, self.op2];
+        let operands: Option<Vec<String>> = parameters.iter()
+            .map(|parameter| Self::parameter_as_str(*parameter))
+            .collect();
+
+        match (self.mnemonic(), operands) {
+            (Some(mnemonic), Some(operands)) => {
+                let count = parameters.iter().rposition(|parameter| *parameter != 0).map_or(0, |last| last + 1);
+                let mut assembly = String::from(mnemonic);
+                for operand in &operands[..count] {
+                    assembly.push(' ');
+                    assembly.push_str(operand);
+                }
+                assembly
+            },
+            _ => format!(".word 0x{:08x}", u32::from(*self)),
+        }
     }
 
-    fn parameter_as_str(parameter: u8) -> String {
+    fn parameter_as_str(parameter: u8) -> Option<String> {
         let mode = (parameter & 0xF0) >> 4;
         if mode >= 0b1000 {
-            format!("{}i", parameter & 0b0111_1111)
+            Some(format!("{}i", parameter & 0b0111_1111))
         } else if mode >= 0b0100 {
-            format!("{}o", parameter & 0b0011_1111)
-        } else if mode >= 0b0001 {
-            format!("{}r", parameter & 0x0F)
+            Some(format!("{}o", offset(parameter)))
+        } else if mode == 0b0001 {
+            Some(format!("{}r", parameter & 0x0F))
         } else if mode == 0 {
-            format!("{}i", parameter & 0x0F)
+            Some(format!("{}s", parameter & 0x0F))
         } else {
-            String::from("xxx")
+            None
         } 
     }
 }
 
+/// Sign extends the 6-bit offset in an offset mode parameter.
+pub fn offset(parameter: u8) -> i8 {
+    ((parameter << 2) as i8) >> 2
+}
+
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.as_assembly())
@@ -167,4 +299,13 @@ mod tests {
     fn from_bytes() {
         assert_eq!(Instruction::from([0xAA, 0x11, 0x22, 0x33]).into_parts(), (0xAA, 0x11, 0x22, 0x33));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn as_assembly() {
+        assert_eq!(Instruction::from([0x00, 0, 0, 0]).as_assembly(), "hlt");
+        assert_eq!(Instruction::from([0x03, 0x80, 0x85, 0]).as_assembly(), "set 0i 5i");
+        assert_eq!(Instruction::from([0x20, 0x12, 0, 0x4F]).as_assembly(), "add 2r 0s 15o");
+        assert_eq!(Instruction::from([0x20, 0x22, 0, 0]).as_assembly(), ".word 0x20220000");
+        assert_eq!(Instruction::from([0xAA, 0x11, 0x22, 0x33]).as_assembly(), ".word 0xaa112233");
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::Word;
+
+/// Where `sti` reads words from and `sto` writes words to.
+pub trait IoDevice {
+    fn read_word(&mut self) -> io::Result<Word>;
+    fn write_word(&mut self, word: Word) -> io::Result<()>;
+}
+
+impl<T: IoDevice + ?Sized> IoDevice for Box<T> {
+    fn read_word(&mut self) -> io::Result<Word> {
+        (**self).read_word()
+    }
+
+    fn write_word(&mut self, word: Word) -> io::Result<()> {
+        (**self).write_word(word)
+    }
+}
+
+/// Reads whitespace separated numbers from stdin and writes one number per line to stdout.
+#[derive(Debug, Default)]
+pub struct StdIo {
+    pending: VecDeque<String>,
+}
+
+impl StdIo {
+    pub fn new() -> Self { Default::default() }
+}
+
+impl IoDevice for StdIo {
+    fn read_word(&mut self) -> io::Result<Word> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "End of input"));
+            }
+            self.pending.extend(line.split_whitespace().map(String::from));
+        }
+
+        let word = self.pending.pop_front().unwrap_or_default();
+        word.parse::<Word>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid input: {}", word)))
+    }
+
+    fn write_word(&mut self, word: Word) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", word)?;
+        stdout.flush()
+    }
+}
+
+/// An in-memory device for tests and embedding.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BufferIo {
+    pub input: VecDeque<Word>,
+    pub output: Vec<Word>,
+}
+
+impl BufferIo {
+    pub fn new<I: IntoIterator<Item = Word>>(input: I) -> Self {
+        Self {
+            input: input.into_iter().collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl IoDevice for BufferIo {
+    fn read_word(&mut self) -> io::Result<Word> {
+        self.input.pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "End of input"))
+    }
+
+    fn write_word(&mut self, word: Word) -> io::Result<()> {
+        self.output.push(word);
+        Ok(())
+    }
+}
+
+/// Reads and writes raw words from a pair of files, each word 8 bytes little-endian.
+#[derive(Debug)]
+pub struct FileIo {
+    input: BufReader<File>,
+    output: BufWriter<File>,
+}
+
+impl FileIo {
+    pub fn new(input: File, output: File) -> Self {
+        Self {
+            input: BufReader::new(input),
+            output: BufWriter::new(output),
+        }
+    }
+
+    /// Opens `input` for reading and creates or truncates `output` for writing.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<Self> {
+        Ok(Self::new(File::open(input)?, File::create(output)?))
+    }
+}
+
+impl IoDevice for FileIo {
+    fn read_word(&mut self) -> io::Result<Word> {
+        let mut buffer = [0; std::mem::size_of::<Word>()];
+        self.input.read_exact(&mut buffer)?;
+        Ok(Word::from_le_bytes(buffer))
+    }
+
+    fn write_word(&mut self, word: Word) -> io::Result<()> {
+        self.output.write_all(&word.to_le_bytes())?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_io() {
+        let mut device = BufferIo::new(vec![1, 2]);
+        assert_eq!(device.read_word().unwrap(), 1);
+        assert_eq!(device.read_word().unwrap(), 2);
+        assert_eq!(device.read_word().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        device.write_word(3).unwrap();
+        assert_eq!(device.output, vec![3]);
+    }
+
+    #[test]
+    fn file_io() {
+        let directory = std::env::temp_dir();
+        let input_path = directory.join(format!("gorp_file_io_in_{}", std::process::id()));
+        let output_path = directory.join(format!("gorp_file_io_out_{}", std::process::id()));
+
+        let mut bytes = 7u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&42u64.to_le_bytes());
+        std::fs::write(&input_path, &bytes).unwrap();
+
+        let mut device = FileIo::open(&input_path, &output_path).unwrap();
+        assert_eq!(device.read_word().unwrap(), 7);
+        assert_eq!(device.read_word().unwrap(), 42);
+        assert!(device.read_word().is_err());
+        device.write_word(9).unwrap();
+        drop(device);
+
+        assert_eq!(std::fs::read(&output_path).unwrap(), 9u64.to_le_bytes().to_vec());
+        std::fs::remove_file(input_path).unwrap();
+        std::fs::remove_file(output_path).unwrap();
+    }
+}
@@ -1,213 +1,875 @@
+pub mod bus;
+pub mod config;
+pub mod devices;
+pub mod error;
+pub mod flags;
+pub mod history;
 pub mod instruction;
-
-use gorp_asm::parse_instruction;
+pub mod io;
+pub mod stats;
+pub mod trace;
+
+use std::convert::TryFrom;
+
+use gorp_asm::assemble_object_at;
+use gorp_asm::object::{Object, ObjectError};
+use self::bus::{Bus, BusError, Device};
+use self::config::CpuConfig;
+use self::error::{CpuError, Trap};
+use self::flags::Flags;
+use self::history::{Change, History, Snapshot};
 use self::instruction::Instruction;
+use self::io::{IoDevice, StdIo};
+use self::stats::Stats;
+use self::trace::{TraceEntry, Tracer, Write};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum State {
     Suspended,
     Running,
+    /// Stopped at a `wfi` until an interrupt is taken.
+    Waiting,
     Halting,
 }
 
-pub struct Cpu {
+/// The outcome of executing a single instruction.
+#[derive(Debug)]
+pub enum StepResult {
+    Running,
+    Halted,
+    Trapped(CpuError),
+}
+
+/// A machine word. Registers, memory cells and I/O are this wide on every host.
+pub type Word = u64;
+
+/// The default number of words at the top of memory reserved for the stack.
+pub const STACK_WORDS: usize = 1024;
+
+/// The number of interrupts, each with a handler address in the vector table.
+pub const INTERRUPTS: usize = 16;
+
+/// The bit set above the flags in the status word saved on interrupt entry
+/// when interrupts were enabled.
+const INTERRUPTS_ENABLED: Word = 1 << 4;
+
+pub struct Cpu<D: IoDevice = StdIo> {
+    config: CpuConfig,
     state: State,
     pc: usize,
-    registers: [usize; 16],
+    /// Index of the top of the stack, which grows down from the end of memory.
+    sp: usize,
+    /// Set by absolute jumps so `step` doesn't advance past the target.
+    jumped: bool,
+    flags: Flags,
+    /// Instructions executed so far, checked against `config.max_cycles`.
+    cycles: u64,
+    /// What each opcode and address has executed so far.
+    stats: Stats,
+    /// Whether raised interrupts are taken, set by `eni` and cleared by `dsi`.
+    interrupts_enabled: bool,
+    /// Raised interrupts that haven't been taken yet, bit n for interrupt n.
+    pending_interrupts: u16,
+    registers: Vec<Word>,
     rom: Vec<Instruction>,
-    memory: Vec<usize>,
+    memory: Vec<Word>,
+    /// Devices mapped over memory addresses.
+    bus: Bus,
+    /// Receives each executed instruction, if tracing.
+    tracer: Option<Box<dyn Tracer>>,
+    /// What the current instruction has written, while tracing.
+    writes: Vec<Write>,
+    /// Undo log of recent steps, for `step_back`.
+    history: History,
+    /// The state before the current step, while keeping history.
+    recording: Option<Snapshot>,
+    io: D,
 }
 
 impl Cpu {
     pub fn new() -> Self { Default::default() }
 
-    pub fn run(&mut self) {
+    pub fn builder() -> CpuConfig {
+        CpuConfig::default()
+    }
+}
+
+impl<D: IoDevice> Cpu<D> {
+    pub fn with_io(io: D) -> Self {
+        Self::with_config(CpuConfig::default(), io)
+    }
+
+    pub fn with_config(config: CpuConfig, io: D) -> Self {
+        Self { 
+            config,
+            state: State::Suspended,
+            pc: 0,
+            sp: config.memory_words,
+            jumped: false,
+            flags: Flags::default(),
+            cycles: 0,
+            stats: Stats::new(),
+            interrupts_enabled: false,
+            pending_interrupts: 0,
+            registers: vec![0; config.registers],
+            memory: vec![0; config.memory_words],
+            rom: Vec::new(),
+            bus: Bus::new(),
+            tracer: None,
+            writes: Vec::new(),
+            history: History::new(config.history_limit),
+            recording: None,
+            io,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), CpuError> {
         self.state = State::Running;
-        while self.pc < self.rom.len() && self.state == State::Running {
-            let next_instruction = self.rom[self.pc];
-            self.process_instruction(next_instruction);
-            self.pc += 1;
+        loop {
+            match self.step() {
+                StepResult::Running => continue,
+                StepResult::Halted => return Ok(()),
+                StepResult::Trapped(error) => return Err(error),
+            }
         }
     }
 
-    pub fn load_bytes(&mut self, bytes: &[u8]) {
-        use std::io::Read;
-        use std::io::BufReader;
+    /// Runs at most `limit` instructions, returning `StepResult::Running`
+    /// if the machine was still running when the limit was reached.
+    pub fn run_for(&mut self, limit: usize) -> StepResult {
+        self.state = State::Running;
+        for _ in 0..limit {
+            match self.step() {
+                StepResult::Running => continue,
+                result => return result,
+            }
+        }
+        StepResult::Running
+    }
 
-        self.rom = Vec::with_capacity(bytes.len() / 4);
+    /// Fetches the instruction at `pc`, executes it, and advances `pc`.
+    /// Then if interrupts are enabled, takes the lowest pending interrupt.
+    pub fn step(&mut self) -> StepResult {
+        if self.state == State::Halting {
+            return StepResult::Halted;
+        }
 
-        let mut instructions = Vec::new();
-        let mut reader = BufReader::new(bytes);
-        let mut buffer = [0; 4];
-        while let Ok(()) = reader.read_exact(&mut buffer) {
-            instructions.push(Instruction::from(buffer));
+        if self.history.limit() > 0 {
+            self.recording = Some(Snapshot {
+                pc: self.pc,
+                sp: self.sp,
+                flags: self.flags,
+                state: self.state,
+                cycles: self.cycles,
+                interrupts_enabled: self.interrupts_enabled,
+                pending_interrupts: self.pending_interrupts,
+                changes: Vec::new(),
+            });
         }
-        self.load_instructions(instructions);
+        let result = self.advance();
+        if let Some(snapshot) = self.recording.take() {
+            // Only keep steps that executed an instruction
+            if snapshot.cycles != self.cycles {
+                self.history.push(snapshot);
+            }
+        }
+        result
+    }
+
+    /// Undoes the last step, returning `false` if there's no history left.
+    ///
+    /// Registers, memory, `pc`, `sp`, flags and interrupt state are restored.
+    /// What devices and I/O did can't be undone, and statistics aren't rewound.
+    pub fn step_back(&mut self) -> bool {
+        let snapshot = match self.history.pop() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+
+        for change in snapshot.changes.iter().rev() {
+            match *change {
+                Change::Register(index, value) => self.registers[index] = value,
+                Change::Memory(address, value) => self.memory[address] = value,
+            }
+        }
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.flags = snapshot.flags;
+        self.state = snapshot.state;
+        self.cycles = snapshot.cycles;
+        self.interrupts_enabled = snapshot.interrupts_enabled;
+        self.pending_interrupts = snapshot.pending_interrupts;
+        true
+    }
+
+    /// The undo log used by `step_back`.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    fn advance(&mut self) -> StepResult {
+        self.state = State::Running;
+
+        let next_instruction = match self.instruction_at(self.pc) {
+            Some(instruction) => instruction,
+            None => {
+                self.state = State::Halting;
+                return StepResult::Halted;
+            },
+        };
+        if let Some(limit) = self.config.max_cycles.filter(|limit| self.cycles >= *limit) {
+            let trap = Trap::CycleLimit(limit);
+            return StepResult::Trapped(CpuError::Trap { pc: self.pc, instruction: next_instruction, trap });
+        }
+        self.cycles += 1;
+        self.stats.record(self.pc, next_instruction.opcode);
+
+        let pc = self.pc;
+        let operands = match self.tracer {
+            Some(_) => self.evaluate_all_parameters(next_instruction).unwrap_or_default(),
+            None => (0, 0, 0),
+        };
+        let result = self.execute(next_instruction);
+        if let Some(tracer) = self.tracer.as_mut() {
+            let (dest, op1, op2) = operands;
+            tracer.trace(&TraceEntry {
+                pc,
+                instruction: next_instruction,
+                operands: [dest, op1, op2],
+                writes: std::mem::take(&mut self.writes),
+            });
+        }
+        if let Err(error) = result {
+            return StepResult::Trapped(error);
+        }
+        self.pending_interrupts |= self.bus.tick();
+        if !self.jumped {
+            self.pc = self.pc.wrapping_add(1);
+        }
+
+        if self.state == State::Halting {
+            return StepResult::Halted;
+        }
+        if let Err(trap) = self.take_interrupt() {
+            return StepResult::Trapped(CpuError::Trap { pc, instruction: next_instruction, trap });
+        }
+        StepResult::Running
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        let pc = self.pc;
+        self.jumped = false;
+        self.process_instruction(instruction)
+            .map_err(|trap| CpuError::Trap { pc, instruction, trap })
+    }
+
+    /// Loads a raw ROM image of 4 byte instructions with no header.
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), CpuError> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(ObjectError::PartialInstruction { length: bytes.len() }.into());
+        }
+
+        let instructions = bytes
+            .chunks_exact(4)
+            .map(|chunk| Instruction::from([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        self.load_instructions(instructions)
+    }
+
+    /// Loads a `.gorp` object file, copying its data segment to the start of
+    /// memory and setting `pc` to its entry point. In von Neumann mode the
+    /// code is copied in after the data, so it wins if the two overlap.
+    pub fn load_object(&mut self, bytes: &[u8]) -> Result<(), CpuError> {
+        let object = Object::from_bytes(bytes)?;
+
+        let assembled = object.profile.code_base.unwrap_or(0) as usize;
+        let loaded = self.config.code_base.unwrap_or(0);
+        if assembled != loaded {
+            return Err(ObjectError::CodeBaseMismatch { assembled, loaded }.into());
+        }
+
+        let capacity = self.memory.len();
+        if object.data.len() > capacity {
+            return Err(ObjectError::DataTooLarge { words: object.data.len(), capacity }.into());
+        }
+        self.memory[..object.data.len()].copy_from_slice(&object.data);
+
+        self.load_instructions(object.rom.into_iter().map(Instruction::from).collect())?;
+        self.pc += object.entry as usize;
+        Ok(())
+    }
+
+    pub fn load_object_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), CpuError> {
+        let bytes = std::fs::read(path)?;
+        self.load_object(&bytes)
     }
 
-    pub fn load_instructions(&mut self, instructions: Vec<Instruction>) {
+    /// Loads a program and points `pc` at its first instruction, emptying
+    /// the stack and clearing the interrupt state, undo history, cycle count
+    /// and stats. In von Neumann mode the program is also copied into memory
+    /// at the code base.
+    pub fn load_instructions(&mut self, instructions: Vec<Instruction>) -> Result<(), CpuError> {
+        if let Some(capacity) = self.config.max_rom.filter(|max| instructions.len() > *max) {
+            return Err(ObjectError::RomTooLarge { instructions: instructions.len(), capacity }.into());
+        }
+
+        let base = self.config.code_base.unwrap_or(0);
+        if self.config.code_base.is_some() {
+            let capacity = self.memory.len().saturating_sub(base);
+            if instructions.len() > capacity {
+                return Err(ObjectError::RomTooLarge { instructions: instructions.len(), capacity }.into());
+            }
+            for (cell, instruction) in self.memory[base..].iter_mut().zip(&instructions) {
+                *cell = u32::from(*instruction) as Word;
+            }
+        }
+
         self.rom = instructions;
+        self.pc = base;
+        self.sp = self.memory.len();
+        self.interrupts_enabled = false;
+        self.pending_interrupts = 0;
+        self.cycles = 0;
+        self.stats.clear();
+        self.history.clear();
+        Ok(())
     }
 
-    pub fn load_assembly(&mut self, assembly: &str) {
-        let instructions: Vec<Instruction> = assembly
-            .lines()
-            .map(|line| line.trim())
-            .filter(|line| !line.is_empty())
-            .map(parse_instruction)
+    pub fn load_assembly(&mut self, assembly: &str) -> Result<(), CpuError> {
+        let instructions: Vec<Instruction> = assemble_object_at(assembly, self.config.code_base.unwrap_or(0))
+            .map_err(CpuError::Asm)?
+            .rom
+            .into_iter()
             .map(Instruction::from)
             .collect();
 
-        self.load_instructions(instructions);
+        self.load_instructions(instructions)
+    }
+
+    pub fn load_assembly_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), CpuError> {
+        let assembly = std::fs::read_to_string(path)?;
+        self.load_assembly(&assembly)
+    }
+
+    pub fn config(&self) -> &CpuConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// The number of instructions executed by `step` so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// How often each opcode and address has executed.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flags = flags;
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    /// Interrupts raised but not yet taken, bit n for interrupt n.
+    pub fn pending_interrupts(&self) -> u16 {
+        self.pending_interrupts
+    }
+
+    /// Raises `interrupt`, to be taken after an instruction once interrupts
+    /// are enabled. Interrupts past the last vector are ignored.
+    pub fn raise(&mut self, interrupt: u8) {
+        self.pending_interrupts |= 1u16.checked_shl(interrupt as u32).unwrap_or(0);
     }
 
-    pub fn load_assembly_file<P: AsRef<std::path::Path>>(&mut self, path: P) {
-        let assembly = std::fs::read_to_string(path).expect("Error reading file");
-        self.load_assembly(&assembly);
+    /// The program as it was loaded. In von Neumann mode it may since have
+    /// been overwritten in memory, so use `instruction_at` to see what will run.
+    pub fn rom(&self) -> &[Instruction] {
+        &self.rom
     }
 
-    pub fn registers(&self) -> &[usize] {
+    /// The instruction `pc` would fetch at `address`. In von Neumann mode
+    /// that's the low 32 bits of the memory word.
+    pub fn instruction_at(&self, address: usize) -> Option<Instruction> {
+        match self.config.code_base {
+            Some(_) => self.memory.get(address).map(|word| Instruction::from(*word as u32)),
+            None => self.rom.get(address).copied(),
+        }
+    }
+
+    pub fn registers(&self) -> &[Word] {
         &self.registers
     }
 
-    pub fn memory(&self) -> &[usize] {
+    pub fn registers_mut(&mut self) -> &mut [Word] {
+        &mut self.registers
+    }
+
+    pub fn memory(&self) -> &[Word] {
         &self.memory
     }
 
-    fn process_instruction(&mut self, instruction: Instruction) {
-        // dbg!(instruction.as_assembly());
-        let (dest, op1, op2) = self.evaluate_all_parameters(instruction);
+    pub fn memory_mut(&mut self) -> &mut [Word] {
+        &mut self.memory
+    }
+
+    /// Maps `device` over the addresses starting at `base`, so `ldr` and
+    /// `str` there read and write the device instead of memory.
+    pub fn attach(&mut self, base: usize, device: Box<dyn Device>) -> Result<(), BusError> {
+        self.bus.attach(base, device)
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    /// Sends every instruction `step` executes to `tracer`, replacing any
+    /// tracer already set.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, returning the tracer so it can be flushed.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.writes.clear();
+        self.tracer.take()
+    }
+
+    pub fn io(&self) -> &D {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut D {
+        &mut self.io
+    }
+
+    fn process_instruction(&mut self, instruction: Instruction) -> Result<(), Trap> {
+        let (dest, op1, op2) = self.evaluate_all_parameters(instruction)?;
 
         match instruction.opcode {
             0x00 => self.state = State::Halting,
-            0x01 => self.registers[dest] = self.memory[op1],
-            0x02 => self.memory[dest] = self.registers[op1],
-            0x03 => self.registers[dest] = op1,
-            0x04 => self.registers[dest] = self.registers[op1],
-            0x10 => if op1 > 0 {
-                if op2 == 0 {
-                    self.pc -= dest
-                } else {
-                    self.pc += dest
+            0x01 => {
+                let value = self.load(op1.wrapping_add(op2))?;
+                self.set_register(dest, value)?;
+            },
+            0x02 => self.store(dest.wrapping_add(op2), self.register(op1)?)?,
+            0x03 => self.set_register(dest, op1)?,
+            0x04 => self.set_register(dest, self.register(op1)?)?,
+            0x05 => self.push(dest)?,
+            0x06 => {
+                let value = self.pop()?;
+                self.set_register(dest, value)?;
+            },
+            0x07 => {
+                // Only skip past the payload once it has all loaded
+                let mut address = self.pc;
+                let mut value = 0;
+                for _ in 0..op1 {
+                    address = address.checked_add(1).ok_or(Trap::PcOutOfBounds)?;
+                    let word = self.instruction_at(address).ok_or(Trap::PcOutOfBounds)?;
+                    value = value << 32 | u32::from(word) as Word;
                 }
+                self.set_register(dest, value)?;
+                self.pc = address;
+            },
+            0x10 => if op1 > 0 {
+                self.jump(dest, op2)?
             },
             0x11 => if op1 == 0 {
-                if op2 == 0 {
-                    self.pc -= dest
-                } else {
-                    self.pc += dest
-                }
+                self.jump(dest, op2)?
+            },
+            0x12 => {
+                self.push(self.pc as Word)?;
+                self.jump(dest, op2)?;
+            },
+            0x13 => {
+                let pc = self.pop()?;
+                self.pc = usize::try_from(pc).map_err(|_| Trap::PcOutOfBounds)?;
+            },
+            0x14 => self.jump(dest, op2)?,
+            0x15 => self.jump_to(dest)?,
+            0x16 => {
+                let address = self.load(dest)?;
+                self.jump_to(address)?;
             },
-            0x20 => self.registers[dest] = op1 + op2,
-            0x21 => self.registers[dest] = op1 - op2,
-            0x22 => self.registers[dest] = op1 * op2,
-            0x23 => self.registers[dest] = op1 / op2,
-            0x24 => self.registers[dest] = op1 % op2,
-            0x30 => self.registers[dest] = if op1 == op2 { 1 } else { 0 },
-            0x31 => self.registers[dest] = if op1 != op2 { 1 } else { 0 },
-            0x32 => self.registers[dest] = if op1 < op2 { 1 } else { 0 },
-            0x33 => self.registers[dest] = if op1 <= op2 { 1 } else { 0 },
-            0x34 => self.registers[dest] = if op1 > op2 { 1 } else { 0 },
-            0x35 => self.registers[dest] = if op1 >= op2 { 1 } else { 0 },
+            0x17 => if self.flags.bits() & op1 as u8 != 0 {
+                self.jump(dest, op2)?
+            },
+            0x18 => if self.flags.bits() & op1 as u8 == 0 {
+                self.jump(dest, op2)?
+            },
+            0x20 => {
+                let result = self.arithmetic(op1, op2, Word::overflowing_add, i64::overflowing_add);
+                self.set_register(dest, result)?;
+            },
+            0x21 => {
+                let result = self.arithmetic(op1, op2, Word::overflowing_sub, i64::overflowing_sub);
+                self.set_register(dest, result)?;
+            },
+            0x22 => {
+                let result = self.arithmetic(op1, op2, Word::overflowing_mul, i64::overflowing_mul);
+                self.set_register(dest, result)?;
+            },
+            0x23 => {
+                let result = op1.checked_div(op2).ok_or(Trap::DivideByZero)?;
+                self.flags = Flags::of(result);
+                self.set_register(dest, result)?;
+            },
+            0x24 => {
+                let result = op1.checked_rem(op2).ok_or(Trap::DivideByZero)?;
+                self.flags = Flags::of(result);
+                self.set_register(dest, result)?;
+            },
+            0x25 => {
+                let result = self.signed_division(op1, op2, i64::overflowing_div)?;
+                self.set_register(dest, result)?;
+            },
+            0x26 => {
+                let result = self.signed_division(op1, op2, i64::overflowing_rem)?;
+                self.set_register(dest, result)?;
+            },
+            0x30 => self.compare(dest, op1, op2, op1 == op2)?,
+            0x31 => self.compare(dest, op1, op2, op1 != op2)?,
+            0x32 => self.compare(dest, op1, op2, op1 < op2)?,
+            0x33 => self.compare(dest, op1, op2, op1 <= op2)?,
+            0x34 => self.compare(dest, op1, op2, op1 > op2)?,
+            0x35 => self.compare(dest, op1, op2, op1 >= op2)?,
+            0x36 => self.compare(dest, op1, op2, (op1 as i64) < op2 as i64)?,
+            0x37 => self.compare(dest, op1, op2, op1 as i64 <= op2 as i64)?,
+            0x38 => self.compare(dest, op1, op2, op1 as i64 > op2 as i64)?,
+            0x39 => self.compare(dest, op1, op2, op1 as i64 >= op2 as i64)?,
+            0x40 => self.set_register(dest, op1 & op2)?,
+            0x41 => self.set_register(dest, op1 | op2)?,
+            0x42 => self.set_register(dest, op1 ^ op2)?,
+            0x43 => self.set_register(dest, !op1)?,
+            0x44 => self.set_register(dest, op1.checked_shl(shift_amount(op2)).unwrap_or(0))?,
+            0x45 => self.set_register(dest, op1.checked_shr(shift_amount(op2)).unwrap_or(0))?,
+            0x46 => self.set_register(dest, ((op1 as i64) >> shift_amount(op2).min(Word::BITS - 1)) as Word)?,
             0x50 => {
-                use std::io::{self, Read};
-
-                let mut buffer = String::new();
-                io::stdin().read_to_string(&mut buffer).expect("Error reading stdin");
-                let value = buffer.parse::<usize>().expect("Input could not be parsed to a usize");
-                self.registers[dest] = value;
+                let value = self.io.read_word().map_err(|error| Trap::IoError(error.to_string()))?;
+                self.set_register(dest, value)?;
             },
             0x51 => {
-                use std::io::{self, Write};
-
-                let output = self.registers[dest].to_string();
-                io::stdout().write_all(output.as_bytes()).expect("Error writing to stdout");
+                let value = self.register(dest)?;
+                self.io.write_word(value).map_err(|error| Trap::IoError(error.to_string()))?;
+            },
+            0x60 => self.enter_interrupt(dest, self.pc + 1)?,
+            0x61 => {
+                for register in (0..self.registers.len()).rev() {
+                    let value = self.pop()?;
+                    self.set_register(register as Word, value)?;
+                }
+                let status = self.pop()?;
+                self.flags = Flags::from_bits(status as u8);
+                self.interrupts_enabled = status & INTERRUPTS_ENABLED != 0;
+                let pc = self.pop()?;
+                self.jump_to(pc)?;
+            },
+            0x62 => self.interrupts_enabled = true,
+            0x63 => self.interrupts_enabled = false,
+            0x64 => {
+                if !self.interrupts_enabled {
+                    return Err(Trap::WaitingWithInterruptsDisabled);
+                }
+                if self.bus.is_empty() && self.pending_interrupts == 0 {
+                    return Err(Trap::WaitingWithoutDevices);
+                }
+                // Stay on the wfi, running it again each step until an interrupt is taken
+                self.state = State::Waiting;
+                self.jumped = true;
             },
-            _ => panic!("Unknown instruction: {}", instruction.opcode),
+            _ => return Err(Trap::UnknownOpcode(instruction.opcode)),
+        }
+        Ok(())
+    }
+
+    /// Takes the lowest numbered pending interrupt if interrupts are enabled.
+    fn take_interrupt(&mut self) -> Result<(), Trap> {
+        if !self.interrupts_enabled || self.pending_interrupts == 0 {
+            return Ok(());
+        }
+
+        let interrupt = self.pending_interrupts.trailing_zeros();
+        self.pending_interrupts &= !(1 << interrupt);
+        // A wfi returns to the instruction after it
+        let return_pc = if self.state == State::Waiting { self.pc + 1 } else { self.pc };
+        let pc = self.pc;
+        let result = self.enter_interrupt(interrupt as Word, return_pc);
+        self.trace_interrupt(pc, interrupt as Word);
+        result
+    }
+
+    /// Traces taking an interrupt as an `int` at `pc`, so its stack writes
+    /// aren't mixed up with the handler's first instruction.
+    fn trace_interrupt(&mut self, pc: usize, interrupt: Word) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(&TraceEntry {
+                pc,
+                instruction: Instruction::from([0x60, 0b1000_0000 | interrupt as u8, 0, 0]),
+                operands: [interrupt, 0, 0],
+                writes: std::mem::take(&mut self.writes),
+            });
+        }
+    }
+
+    /// Pushes `return_pc`, the flags and every register, then jumps to the
+    /// handler in the vector table with interrupts disabled.
+    fn enter_interrupt(&mut self, interrupt: Word, return_pc: usize) -> Result<(), Trap> {
+        if interrupt >= INTERRUPTS as Word {
+            return Err(Trap::UnhandledInterrupt(interrupt));
+        }
+        let entry = (self.config.vector_table as Word).checked_add(interrupt)
+            .ok_or(Trap::UnhandledInterrupt(interrupt))?;
+        let handler = self.load(entry)?;
+        if handler == 0 {
+            return Err(Trap::UnhandledInterrupt(interrupt));
+        }
+
+        let status = self.flags.bits() as Word | if self.interrupts_enabled { INTERRUPTS_ENABLED } else { 0 };
+        self.push(return_pc as Word)?;
+        self.push(status)?;
+        for register in 0..self.registers.len() {
+            self.push(self.registers[register])?;
+        }
+
+        self.interrupts_enabled = false;
+        self.state = State::Running;
+        self.jump_to(handler)
+    }
+
+    /// Wrapping arithmetic that sets the flags, using the signed version of
+    /// the operation to detect overflow.
+    fn arithmetic(
+        &mut self,
+        x: Word,
+        y: Word,
+        unsigned: fn(Word, Word) -> (Word, bool),
+        signed: fn(i64, i64) -> (i64, bool),
+    ) -> Word {
+        let (result, carry) = unsigned(x, y);
+        let (_, overflow) = signed(x as i64, y as i64);
+        self.flags = Flags { carry, overflow, ..Flags::of(result) };
+        result
+    }
+
+    /// Two's complement division, where only `MIN / -1` overflows.
+    fn signed_division(
+        &mut self,
+        x: Word,
+        y: Word,
+        operation: fn(i64, i64) -> (i64, bool),
+    ) -> Result<Word, Trap> {
+        if y == 0 {
+            return Err(Trap::DivideByZero);
+        }
+        let (result, overflow) = operation(x as i64, y as i64);
+        self.flags = Flags { overflow, ..Flags::of(result as Word) };
+        Ok(result as Word)
+    }
+
+    /// Stores the result of a comparison, setting the flags as if `y` was subtracted from `x`.
+    fn compare(&mut self, dest: Word, x: Word, y: Word, result: bool) -> Result<(), Trap> {
+        self.arithmetic(x, y, Word::overflowing_sub, i64::overflowing_sub);
+        self.set_register(dest, if result { 1 } else { 0 })
+    }
+
+    fn jump(&mut self, distance: Word, sign: Word) -> Result<(), Trap> {
+        let distance = usize::try_from(distance).map_err(|_| Trap::PcOutOfBounds)?;
+        self.pc = if sign == 0 {
+            // Jumping back to 0 wraps to the address before it, which the
+            // step then advances from
+            self.pc.checked_add(1)
+                .and_then(|next| next.checked_sub(distance))
+                .map(|target| target.wrapping_sub(1))
+        } else {
+            self.pc.checked_add(distance)
+        }.ok_or(Trap::PcOutOfBounds)?;
+        Ok(())
+    }
+
+    fn jump_to(&mut self, address: Word) -> Result<(), Trap> {
+        self.pc = usize::try_from(address).map_err(|_| Trap::PcOutOfBounds)?;
+        self.jumped = true;
+        Ok(())
+    }
+
+    fn push(&mut self, value: Word) -> Result<(), Trap> {
+        if self.sp <= self.memory.len().saturating_sub(self.config.stack_words) {
+            return Err(Trap::StackOverflow);
+        }
+        self.sp -= 1;
+        self.remember(Change::Memory(self.sp, self.memory[self.sp]));
+        self.memory[self.sp] = value;
+        self.record(Write::Memory(self.sp as Word, value));
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Word, Trap> {
+        if self.sp >= self.memory.len() {
+            return Err(Trap::StackUnderflow);
+        }
+        let value = self.memory[self.sp];
+        self.sp += 1;
+        Ok(value)
+    }
+
+    fn register(&self, index: Word) -> Result<Word, Trap> {
+        index_of(index, &self.registers).map(|index| self.registers[index]).ok_or(Trap::InvalidRegister(index))
+    }
+
+    fn set_register(&mut self, index: Word, value: Word) -> Result<(), Trap> {
+        let register = index_of(index, &self.registers).ok_or(Trap::InvalidRegister(index))?;
+        self.remember(Change::Register(register, self.registers[register]));
+        self.registers[register] = value;
+        self.record(Write::Register(register, value));
+        Ok(())
+    }
+
+    fn load(&mut self, address: Word) -> Result<Word, Trap> {
+        if let Some(result) = self.bus.read(address) {
+            return result.map_err(|error| Trap::IoError(error.to_string()));
         }
-        // dbg!(&self);
+        index_of(address, &self.memory).map(|index| self.memory[index]).ok_or(Trap::MemoryFault(address))
     }
 
-    fn evaluate_parameter(&self, parameter: u8) -> usize {
+    fn store(&mut self, address: Word, value: Word) -> Result<(), Trap> {
+        if let Some(result) = self.bus.write(address, value) {
+            result.map_err(|error| Trap::IoError(error.to_string()))?;
+            self.record(Write::Memory(address, value));
+            return Ok(());
+        }
+        let cell = index_of(address, &self.memory).ok_or(Trap::MemoryFault(address))?;
+        self.remember(Change::Memory(cell, self.memory[cell]));
+        self.memory[cell] = value;
+        self.record(Write::Memory(address, value));
+        Ok(())
+    }
+
+    /// Notes a write for the tracer, if there is one.
+    fn record(&mut self, write: Write) {
+        if self.tracer.is_some() {
+            self.writes.push(write);
+        }
+    }
+
+    /// Notes an old value for the undo log, if keeping history.
+    fn remember(&mut self, change: Change) {
+        if let Some(snapshot) = self.recording.as_mut() {
+            snapshot.changes.push(change);
+        }
+    }
+
+    fn evaluate_parameter(&self, parameter: u8) -> Result<Word, Trap> {
         let mode = (parameter & 0xF0) >> 4;
         if mode >= 0b1000 {
             // Immediate mode
-            (parameter & 0b0111_1111) as usize
+            Ok((parameter & 0b0111_1111) as Word)
         } else if mode >= 0b0100 {
-            // Offset mode
-            // Not sure how I'm going to use this yet or what it's even for
-            // I read a bit about addressing but I want to implement some stuff
-            // before I read further
-            let offset = (parameter & 0b0011_1111) as usize;
-            self.pc + offset
-        } else if mode >= 0b0001 {
+            // PC-relative offset mode
+            let offset = instruction::offset(parameter) as i64;
+            Ok((self.pc as Word).wrapping_add(offset as Word))
+        } else if mode == 0b0001 {
             // Register mode
-            self.registers[(parameter & 0x0F) as usize]
+            self.register((parameter & 0x0F) as Word)
         } else if mode == 0 {
             // Immediate mode
-            (parameter & 0b0000_1111) as usize
+            Ok((parameter & 0b0000_1111) as Word)
         } else {
-            panic!("Invalid mode: {}", mode);
+            Err(Trap::InvalidMode(mode))
         } 
     }
 
-    fn evaluate_all_parameters(&self, instruction: Instruction) -> (usize, usize, usize) {
+    fn evaluate_all_parameters(&self, instruction: Instruction) -> Result<(Word, Word, Word), Trap> {
         let (_, dest, op1, op2) = instruction.into_parts();
-        (self.evaluate_parameter(dest), self.evaluate_parameter(op1), self.evaluate_parameter(op2))
+        Ok((self.evaluate_parameter(dest)?, self.evaluate_parameter(op1)?, self.evaluate_parameter(op2)?))
     }
+}
 
-    // pub fn input(&mut self) {
-    //     use std::io::Read;
-    //     let mut buffer = String::new();
-    //     std::io::stdin().read_to_string(&mut buffer).expect("Error reading stdin");
-    // }
+/// Shift amounts too big for a `u32` shift everything out just like `Word::BITS` does.
+fn shift_amount(amount: Word) -> u32 {
+    u32::try_from(amount).unwrap_or(u32::MAX)
+}
 
-    // pub fn output<'a, T: AsRef<[u8]>>(&self, value: T) {
-    //     use std::io::Write;
-    //     std::io::stdout().write_all(value.as_ref()).expect("Error write to stdout");
-    // }
+/// Converts a word to an index into `slice`, if it's in bounds.
+fn index_of<T>(word: Word, slice: &[T]) -> Option<usize> {
+    usize::try_from(word).ok().filter(|index| *index < slice.len())
 }
 
-impl Default for Cpu {
+impl<D: IoDevice + Default> Default for Cpu<D> {
     fn default() -> Self {
-        Self { 
-            state: State::Suspended,
-            pc: 0,
-            registers: [0; 16],
-            memory: vec![0; 65536],
-            rom: Vec::new(),
-        }
+        Self::with_io(D::default())
     }
 }
 
-impl std::fmt::Debug for Cpu {
+impl<D: IoDevice> std::fmt::Debug for Cpu<D> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Cpu {{")?;
         writeln!(f, "\tstate: {:?}", self.state)?;
         writeln!(f, "\tpc: {}", self.pc)?;
+        writeln!(f, "\tsp: {}", self.sp)?;
+        writeln!(f, "\tcycles: {}", self.cycles)?;
+        writeln!(f, "\tflags: {}", self.flags)?;
+        writeln!(f, "\tinterrupts: {}, pending {:016b}", if self.interrupts_enabled { "enabled" } else { "disabled" }, self.pending_interrupts)?;
         writeln!(f, "\tregisters: {:?}", &self.registers)?;
         writeln!(f, "\tmemory: {} values", self.memory.len())?;
+        if self.tracer.is_some() {
+            writeln!(f, "\ttracing")?;
+        }
+        if !self.bus.is_empty() {
+            writeln!(f, "\tbus: {:?}", self.bus)?;
+        }
         writeln!(f, "\trom: {} instructions", self.rom.len())?;
         writeln!(f, "}}")?;
         Ok(())
     }
 }
 
-impl std::ops::ShlAssign<Instruction> for Cpu {
+impl<D: IoDevice> std::ops::ShlAssign<Instruction> for Cpu<D> {
     fn shl_assign(&mut self, rhs: Instruction) {
-        self.process_instruction(rhs);
+        self.execute(rhs).expect("Error executing instruction");
     }
 }
 
-impl std::ops::ShlAssign<[u8; 4]> for Cpu {
+impl<D: IoDevice> std::ops::ShlAssign<[u8; 4]> for Cpu<D> {
     fn shl_assign(&mut self, rhs: [u8; 4]) {
-        self.process_instruction(Instruction::from(rhs));
+        self.execute(Instruction::from(rhs)).expect("Error executing instruction");
     }
 }
 
-impl std::ops::ShlAssign<&str> for Cpu {
+impl<D: IoDevice> std::ops::ShlAssign<&str> for Cpu<D> {
     fn shl_assign(&mut self, rhs: &str) {
-        self.process_instruction(Instruction::from(rhs))
+        let instruction = Instruction::try_from(rhs).expect("Error parsing instruction");
+        self.execute(instruction).expect("Error executing instruction");
     }
 }
 
@@ -220,6 +882,7 @@ impl std::ops::ShlAssign<&str> for Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::io::BufferIo;
     
     // Add register mode to a number
     fn r(num: u8) -> u8 {
@@ -306,6 +969,156 @@ mod tests {
         assert_eq!(cpu.registers[3], 2);
     }
     
+    #[test]
+    fn wrapping_arithmetic() {
+        let mut cpu = Cpu::new();
+
+        cpu <<= [0x21, 0, 0, 1];
+        assert_eq!(cpu.registers[0], Word::MAX);
+        assert_eq!(cpu.flags, Flags { carry: true, negative: true, ..Default::default() });
+
+        cpu <<= [0x20, 1, r(0), 1];
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.flags, Flags { zero: true, carry: true, ..Default::default() });
+
+        cpu.registers[2] = i64::MAX as Word;
+        cpu <<= [0x20, 3, r(2), 1];
+        assert_eq!(cpu.registers[3], i64::MIN as Word);
+        assert_eq!(cpu.flags, Flags { overflow: true, negative: true, ..Default::default() });
+
+        cpu <<= [0x22, 3, r(2), 4];
+        assert_eq!(cpu.registers[3], (i64::MAX as Word).wrapping_mul(4));
+        assert!(cpu.flags.carry && cpu.flags.overflow);
+
+        cpu <<= [0x23, 3, 4, 2];
+        assert_eq!(cpu.flags, Flags::default());
+    }
+
+    #[test]
+    fn comparison_flags() {
+        let mut cpu = Cpu::new();
+
+        cpu <<= [0x30, 0, 5, 5];
+        assert_eq!(cpu.registers[0], 1);
+        assert_eq!(cpu.flags, Flags { zero: true, ..Default::default() });
+
+        cpu <<= [0x32, 0, 3, 5];
+        assert_eq!(cpu.registers[0], 1);
+        assert_eq!(cpu.flags, Flags { carry: true, negative: true, ..Default::default() });
+    }
+
+    #[test]
+    fn jump_on_flags() {
+        let mut cpu = Cpu::new();
+        cpu.flags = Flags { zero: true, ..Default::default() };
+
+        cpu <<= [0x17, 5, flags::ZERO, 1];
+        assert_eq!(cpu.pc, 5);
+
+        cpu <<= [0x17, 5, flags::CARRY | flags::NEGATIVE, 1];
+        assert_eq!(cpu.pc, 5);
+
+        cpu <<= [0x18, 5, flags::ZERO, 1];
+        assert_eq!(cpu.pc, 5);
+
+        cpu <<= [0x18, 3, flags::CARRY, 0];
+        assert_eq!(cpu.pc, 2);
+    }
+
+    #[test]
+    fn and() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 0b1100, 0];
+        cpu <<= [0x03, 1, 0b1010, 0];
+
+        cpu <<= [0x40, 2, r(0), r(1)];
+        assert_eq!(cpu.registers[2], 0b1000);
+
+        cpu <<= [0x40, 2, r(0), 4];
+        assert_eq!(cpu.registers[2], 4);
+    }
+
+    #[test]
+    fn or() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 0b1100, 0];
+        cpu <<= [0x03, 1, 0b1010, 0];
+
+        cpu <<= [0x41, 2, r(0), r(1)];
+        assert_eq!(cpu.registers[2], 0b1110);
+
+        cpu <<= [0x41, 2, r(0), 1];
+        assert_eq!(cpu.registers[2], 0b1101);
+    }
+
+    #[test]
+    fn xor() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 0b1100, 0];
+        cpu <<= [0x03, 1, 0b1010, 0];
+
+        cpu <<= [0x42, 2, r(0), r(1)];
+        assert_eq!(cpu.registers[2], 0b0110);
+
+        cpu <<= [0x42, 2, r(2), r(2)];
+        assert_eq!(cpu.registers[2], 0);
+    }
+
+    #[test]
+    fn not() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 5, 0];
+
+        cpu <<= [0x43, 1, r(0), 0];
+        assert_eq!(cpu.registers[1], !5);
+
+        cpu <<= [0x43, 1, r(1), 0];
+        assert_eq!(cpu.registers[1], 5);
+    }
+
+    #[test]
+    fn shift_left() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 3, 0];
+
+        cpu <<= [0x44, 1, r(0), 2];
+        assert_eq!(cpu.registers[1], 12);
+
+        cpu.registers[2] = 64;
+        cpu <<= [0x44, 1, r(0), r(2)];
+        assert_eq!(cpu.registers[1], 0);
+    }
+
+    #[test]
+    fn shift_right() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 12, 0];
+
+        cpu <<= [0x45, 1, r(0), 2];
+        assert_eq!(cpu.registers[1], 3);
+
+        cpu.registers[0] = Word::MAX;
+        cpu <<= [0x45, 1, r(0), 4];
+        assert_eq!(cpu.registers[1], Word::MAX >> 4);
+    }
+
+    #[test]
+    fn shift_arithmetic() {
+        let mut cpu = Cpu::new();
+        cpu <<= [0x03, 0, 12, 0];
+
+        cpu <<= [0x46, 1, r(0), 2];
+        assert_eq!(cpu.registers[1], 3);
+
+        cpu.registers[0] = -16i64 as Word;
+        cpu <<= [0x46, 1, r(0), 2];
+        assert_eq!(cpu.registers[1], -4i64 as Word);
+
+        cpu.registers[2] = 1000;
+        cpu <<= [0x46, 1, r(0), r(2)];
+        assert_eq!(cpu.registers[1], Word::MAX);
+    }
+
     #[test]
     fn multiply() {
         let mut cpu = Cpu::new();
@@ -354,6 +1167,64 @@ mod tests {
         assert_eq!(cpu.registers[3], 3);
     }
 
+    #[test]
+    fn signed_divide() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = -7i64 as Word;
+        cpu.registers[1] = 2;
+
+        cpu <<= [0x25, 2, r(0), r(1)];
+        assert_eq!(cpu.registers[2] as i64, -3);
+
+        cpu <<= [0x25, 2, r(2), r(2)];
+        assert_eq!(cpu.registers[2], 1);
+
+        cpu.registers[3] = i64::MIN as Word;
+        cpu.registers[4] = -1i64 as Word;
+        cpu <<= [0x25, 2, r(3), r(4)];
+        assert_eq!(cpu.registers[2], i64::MIN as Word);
+        assert!(cpu.flags.overflow);
+
+        let result = cpu.execute(Instruction::from([0x25, 2, r(0), 0]));
+        assert_eq!(trap_of(result), Trap::DivideByZero);
+    }
+
+    #[test]
+    fn signed_modulo() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = -7i64 as Word;
+        cpu.registers[1] = 3;
+
+        cpu <<= [0x26, 2, r(0), r(1)];
+        assert_eq!(cpu.registers[2] as i64, -1);
+
+        cpu <<= [0x26, 2, r(1), r(0)];
+        assert_eq!(cpu.registers[2], 3);
+
+        let result = cpu.execute(Instruction::from([0x26, 2, r(0), 0]));
+        assert_eq!(trap_of(result), Trap::DivideByZero);
+    }
+
+    #[test]
+    fn signed_comparisons() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = -1i64 as Word;
+
+        cpu <<= [0x32, 1, r(0), 1];
+        assert_eq!(cpu.registers[1], 0);
+        cpu <<= [0x36, 1, r(0), 1];
+        assert_eq!(cpu.registers[1], 1);
+
+        cpu <<= [0x37, 1, r(0), r(0)];
+        assert_eq!(cpu.registers[1], 1);
+
+        cpu <<= [0x38, 1, r(0), 0];
+        assert_eq!(cpu.registers[1], 0);
+
+        cpu <<= [0x39, 1, 0, r(0)];
+        assert_eq!(cpu.registers[1], 1);
+    }
+
     #[test]
     fn jump_if_true() {
         let mut cpu = Cpu::new();
@@ -386,6 +1257,50 @@ mod tests {
         assert_eq!(cpu.pc, 2);
     }
 
+    #[test]
+    fn jump_always() {
+        let mut cpu = Cpu::new();
+
+        cpu <<= [0x14, 5, 0, 1];
+        assert_eq!(cpu.pc, 5);
+
+        cpu <<= [0x14, 3, 0, 0];
+        assert_eq!(cpu.pc, 2);
+    }
+
+    #[test]
+    fn jump_absolute() {
+        let mut cpu = Cpu::new();
+        cpu.registers[3] = 300;
+        cpu.memory[10] = 42;
+
+        cpu <<= [0x15, 0b1000_0000 | 20, 0, 0];
+        assert_eq!(cpu.pc, 20);
+
+        cpu <<= [0x15, r(3), 0, 0];
+        assert_eq!(cpu.pc, 300);
+
+        cpu <<= [0x16, 0b1000_0000 | 10, 0, 0];
+        assert_eq!(cpu.pc, 42);
+    }
+
+    #[test]
+    fn jump_table() {
+        let mut cpu = Cpu::new();
+        cpu.load_instructions(vec![
+            Instruction::from([0x03, 0, 0b1000_0000 | 100, 0]),
+            Instruction::from([0x03, 1, 0b1000_0000 | 5, 0]),
+            Instruction::from([0x02, 0b1000_0000 | 100, 1, 0]),
+            Instruction::from([0x16, r(0), 0, 0]),
+            Instruction::from([0x03, 2, 0b1000_0000 | 1, 0]),
+            Instruction::from([0x00, 0, 0, 0]),
+        ]).unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0);
+        assert_eq!(cpu.pc, 6);
+    }
+
     #[test]
     fn is_equal() {
         let mut cpu = Cpu::new();
@@ -486,13 +1401,601 @@ mod tests {
             0, 0, 0, 0,
             0, 0, 0, 0,
             0x03, 2, 9, 0,
-            0,
-        ]);
-        cpu.run();
+        ]).unwrap();
+        cpu.run().unwrap();
 
         assert_eq!(cpu.registers[0], 1);
         assert_eq!(cpu.registers[1], 8);
         assert_eq!(cpu.registers[2], 9);
         assert_eq!(cpu.registers[3], 0);
     }
+
+    #[test]
+    fn jump_back_to_start() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("
+            loop: add 0 0r 1
+            let 1 0r 3
+            jpt loop 1r
+        ").unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 3);
+    }
+
+    fn trap_of(result: Result<(), CpuError>) -> Trap {
+        result.unwrap_err().trap().cloned().unwrap()
+    }
+
+    #[test]
+    fn divide_by_zero_traps() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = 5;
+
+        let result = cpu.execute(Instruction::from([0x23, 1, r(0), 0]));
+        assert_eq!(trap_of(result), Trap::DivideByZero);
+
+        let result = cpu.execute(Instruction::from([0x24, 1, r(0), 0]));
+        assert_eq!(trap_of(result), Trap::DivideByZero);
+    }
+
+    #[test]
+    fn unknown_opcode_traps() {
+        let mut cpu = Cpu::new();
+        let result = cpu.execute(Instruction::from([0xFF, 0, 0, 0]));
+        assert_eq!(trap_of(result), Trap::UnknownOpcode(0xFF));
+    }
+
+    #[test]
+    fn memory_fault_traps() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = 70000;
+
+        let result = cpu.execute(Instruction::from([0x01, 1, r(0), 0]));
+        assert_eq!(trap_of(result), Trap::MemoryFault(70000));
+
+        let result = cpu.execute(Instruction::from([0x02, r(0), 1, 0]));
+        assert_eq!(trap_of(result), Trap::MemoryFault(70000));
+    }
+
+    #[test]
+    fn invalid_register_traps() {
+        let mut cpu = Cpu::new();
+        let result = cpu.execute(Instruction::from([0x03, 0x80 | 16, 1, 0]));
+        assert_eq!(trap_of(result), Trap::InvalidRegister(16));
+    }
+
+    #[test]
+    fn invalid_mode_traps() {
+        let mut cpu = Cpu::new();
+        let result = cpu.execute(Instruction::from([0x03, 0, 0b0010_0000, 0]));
+        assert_eq!(trap_of(result), Trap::InvalidMode(0b0010));
+    }
+
+    #[test]
+    fn pc_underflow_traps() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = 1;
+        cpu.load_instructions(vec![
+            Instruction::from([0x03, 0, 1, 0]),
+            Instruction::from([0x10, 5, r(0), 0]),
+        ]).unwrap();
+
+        match cpu.run() {
+            Err(CpuError::Trap { pc, instruction, trap }) => {
+                assert_eq!(pc, 1);
+                assert_eq!(instruction, Instruction::from([0x10, 5, r(0), 0]));
+                assert_eq!(trap, Trap::PcOutOfBounds);
+            },
+            other => panic!("Expected a trap, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn step_through_program() {
+        let mut cpu = Cpu::new();
+        cpu.load_instructions(vec![
+            Instruction::from([0x03, 0, 1, 0]),
+            Instruction::from([0x20, 0, r(0), 2]),
+            Instruction::from([0x00, 0, 0, 0]),
+            Instruction::from([0x03, 0, 9, 0]),
+        ]).unwrap();
+
+        assert!(matches!(cpu.step(), StepResult::Running));
+        assert_eq!(cpu.pc, 1);
+        assert_eq!(cpu.registers[0], 1);
+
+        assert!(matches!(cpu.step(), StepResult::Running));
+        assert_eq!(cpu.registers[0], 3);
+
+        assert!(matches!(cpu.step(), StepResult::Halted));
+        assert!(matches!(cpu.step(), StepResult::Halted));
+        assert_eq!(cpu.pc, 3);
+        assert_eq!(cpu.registers[0], 3);
+    }
+
+    #[test]
+    fn step_traps() {
+        let mut cpu = Cpu::new();
+        cpu.load_instructions(vec![Instruction::from([0x23, 0, 1, 0])]).unwrap();
+
+        match cpu.step() {
+            StepResult::Trapped(error) => assert_eq!(error.trap(), Some(&Trap::DivideByZero)),
+            other => panic!("Expected a trap, got {:?}", other),
+        }
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn run_for_stops_infinite_loop() {
+        let mut cpu = Cpu::new();
+        cpu.load_instructions(vec![
+            Instruction::from([0x03, 0, 0, 0]),
+            Instruction::from([0x20, 0, r(0), 1]),
+            Instruction::from([0x10, 2, 1, 0]),
+        ]).unwrap();
+
+        assert!(matches!(cpu.run_for(10), StepResult::Running));
+        assert_eq!(cpu.registers[0], 5);
+
+        cpu.load_instructions(vec![Instruction::from([0x00, 0, 0, 0])]).unwrap();
+        cpu.pc = 0;
+        assert!(matches!(cpu.run_for(10), StepResult::Halted));
+    }
+
+    #[test]
+    fn configured_machine() {
+        let mut cpu = Cpu::builder().memory_words(16).registers(2).stack_words(4).max_rom(3).build();
+        assert_eq!(cpu.memory.len(), 16);
+        assert_eq!(cpu.sp, 16);
+
+        let result = cpu.execute(Instruction::from([0x03, 2, 1, 0]));
+        assert_eq!(trap_of(result), Trap::InvalidRegister(2));
+
+        let result = cpu.load_assembly("hlt\nhlt\nhlt\nhlt");
+        assert!(matches!(result, Err(CpuError::Object(ObjectError::RomTooLarge { instructions: 4, capacity: 3 }))));
+
+        for _ in 0..4 {
+            cpu <<= [0x05, 1, 0, 0];
+        }
+        let result = cpu.execute(Instruction::from([0x05, 1, 0, 0]));
+        assert_eq!(trap_of(result), Trap::StackOverflow);
+    }
+
+    #[test]
+    fn von_neumann() {
+        let mut cpu = Cpu::builder().code_base(16).build();
+        cpu.load_assembly("
+            set 1 0
+            ldr 4 16    ; read the first instruction
+            str 20 1    ; overwrite the last with a hlt
+            set 2 5
+            set 3 7
+        ").unwrap();
+        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.memory[16], 0x0381_8000);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[4], 0x0381_8000);
+        assert_eq!(cpu.registers[2], 5);
+        assert_eq!(cpu.registers[3], 0);
+        assert_eq!(cpu.pc, 21);
+        assert_eq!(cpu.instruction_at(20), Some(Instruction::from(0)));
+        assert_eq!(cpu.rom()[4], Instruction::from([0x03, 131, 135, 0]));
+
+        let mut cpu = Cpu::builder().code_base(16).build();
+        cpu.load_assembly("
+            set 0 end
+            jpa 0r
+            hlt
+            end: set 1 1
+        ").unwrap();
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1], 1);
+
+        let mut cpu = Cpu::builder().memory_words(20).code_base(16).build();
+        let result = cpu.load_assembly("hlt\nhlt\nhlt\nhlt\nhlt");
+        assert!(matches!(result, Err(CpuError::Object(ObjectError::RomTooLarge { instructions: 5, capacity: 4 }))));
+    }
+
+    #[test]
+    fn base_offset_addressing() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("
+            set 3 100   ; an array at 100
+            set 1 7
+            str 3r 1 2  ; array[2] = 7
+            ldr 0 3r 2
+            add 4 3r 1
+            ldr 5 4r 1  ; array[1 + 1]
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory[102], 7);
+        assert_eq!(cpu.registers[0], 7);
+        assert_eq!(cpu.registers[5], 7);
+    }
+
+    #[test]
+    fn pc_relative_addressing() {
+        let mut cpu = Cpu::builder().code_base(0).build();
+        cpu.load_assembly("
+            set 0 5
+            ldr 1 -1o   ; the word before this one
+            ldr 2 2o
+            hlt
+            .word 42
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1], 0x0380_8500);
+        assert_eq!(cpu.registers[2], 42);
+    }
+
+    #[test]
+    fn memory_mapped_devices() {
+        use crate::devices::{Framebuffer, Timer};
+
+        let mut cpu = Cpu::new();
+        cpu.attach(100, Box::new(Timer::new())).unwrap();
+        cpu.attach(110, Box::new(Framebuffer::with_output(2, 1, Vec::new()))).unwrap();
+        cpu.load_assembly("
+            set 0 'h'
+            str 110 0
+            set 0 'i'
+            str 111 0
+            str 112 0   ; draw
+            ldr 1 100   ; instructions run so far
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1], 5);
+        assert_eq!(cpu.memory[110], 0);
+        assert_eq!(format!("{:?}", cpu.bus()), "{100..102: \"timer\", 110..113: \"framebuffer\"}");
+        assert!(cpu.attach(111, Box::new(Timer::new())).is_err());
+    }
+
+    #[test]
+    fn software_interrupts() {
+        let mut cpu = Cpu::builder().vector_table(100).build();
+        cpu.load_assembly("
+            set 0 6
+            str 101 0   ; handler for interrupt 1
+            set 2 7
+            int 1
+            add 3 2r 1
+            hlt
+            set 2 99    ; handler
+            iret
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.registers[3], 8);
+        assert_eq!(cpu.sp(), cpu.memory.len());
+        assert!(!cpu.interrupts_enabled());
+
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("int 2").unwrap();
+        assert_eq!(trap_of(cpu.run()), Trap::UnhandledInterrupt(2));
+
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("wfi").unwrap();
+        assert_eq!(trap_of(cpu.run()), Trap::WaitingWithInterruptsDisabled);
+
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("eni\nwfi").unwrap();
+        assert_eq!(trap_of(cpu.run()), Trap::WaitingWithoutDevices);
+        assert_eq!(cpu.pc(), 1);
+
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("psh 1\neni").unwrap();
+        cpu.run().unwrap();
+        cpu.raise(1);
+        cpu.load_assembly("hlt").unwrap();
+        assert!(!cpu.interrupts_enabled());
+        assert_eq!(cpu.pending_interrupts(), 0);
+        assert_eq!(cpu.sp(), cpu.memory().len());
+
+        let mut cpu = Cpu::builder().vector_table(usize::MAX).build();
+        cpu.load_assembly("int 3").unwrap();
+        assert_eq!(trap_of(cpu.run()), Trap::UnhandledInterrupt(3));
+    }
+
+    #[test]
+    fn timer_interrupts() {
+        use crate::devices::Timer;
+
+        let mut cpu = Cpu::builder().vector_table(100).max_cycles(1000).build();
+        cpu.attach(120, Box::new(Timer::every(6, 0))).unwrap();
+        cpu.load_assembly("
+            set 0 7
+            str 100 0   ; handler for the timer
+            eni
+            wfi
+            wfi
+            hlt
+            hlt
+            ldr 1 110   ; handler counts interrupts in memory, since iret restores r1
+            add 1 1r 1
+            str 110 1
+            iret
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory[110], 2);
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.pc(), 6);
+        assert_eq!(cpu.state(), &State::Halting);
+        assert!(cpu.interrupts_enabled());
+
+        let mut cpu = Cpu::builder().vector_table(100).build();
+        cpu.load_assembly("set 0 5\nstr 102 0\nset 1 1\neni\nhlt\niret").unwrap();
+        cpu.raise(2);
+        assert!(matches!(cpu.run_for(3), StepResult::Running));
+        assert_eq!(cpu.pending_interrupts(), 0b100);
+        cpu.step();
+        assert_eq!((cpu.pc(), cpu.pending_interrupts()), (5, 0));
+    }
+
+    #[test]
+    fn execution_stats() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("
+            set 0 3
+            loop: sub 0 0r 1
+            jpt loop 0r
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.cycles(), 7);
+        assert_eq!(cpu.stats().instructions(), 7);
+        assert_eq!(cpu.stats().opcode_count(0x21), 3);
+        assert_eq!(cpu.stats().hits(0), 1);
+        assert_eq!(cpu.stats().hits(2), 3);
+        assert_eq!(cpu.stats().hottest()[0], (1, 3));
+
+        cpu.load_assembly("hlt").unwrap();
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.stats().instructions(), 0);
+    }
+
+    #[test]
+    fn tracing() {
+        use crate::trace::TraceWriter;
+        use std::sync::mpsc;
+
+        let (sender, receiver) = mpsc::channel();
+        let mut cpu = Cpu::new();
+        cpu.set_tracer(Box::new(move |entry: &TraceEntry| sender.send(entry.clone()).unwrap()));
+        cpu.load_assembly("
+            set 1 5
+            str 10 1 2
+            psh 1r
+            set 2 0x1000000
+            str 2r 1
+        ").unwrap();
+
+        assert_eq!(trap_of(cpu.run()), Trap::MemoryFault(0x100_0000));
+        let entries: Vec<TraceEntry> = receiver.try_iter().collect();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].writes, vec![Write::Register(1, 5)]);
+        assert_eq!(entries[1].operands, [10, 1, 2]);
+        assert_eq!(entries[1].writes, vec![Write::Memory(12, 5)]);
+        assert_eq!(entries[2].writes, vec![Write::Memory(65535, 5)]);
+        assert_eq!(entries[3].writes, vec![Write::Register(2, 0x100_0000)]);
+        assert_eq!((entries[4].pc, entries[4].writes.len()), (5, 0));
+
+        let mut cpu = Cpu::new();
+        cpu.set_tracer(Box::new(TraceWriter::new(Vec::new()).unwrap()));
+        cpu.load_assembly("set 0 1\nadd 0 0r 0r").unwrap();
+        cpu.run().unwrap();
+        let mut tracer = cpu.take_tracer().unwrap();
+        tracer.flush().unwrap();
+        assert!(cpu.take_tracer().is_none());
+
+        let (sender, receiver) = mpsc::channel();
+        let mut cpu = Cpu::builder().vector_table(100).registers(2).build();
+        cpu.attach(120, Box::new(crate::devices::Timer::every(3, 1))).unwrap();
+        cpu.set_tracer(Box::new(move |entry: &TraceEntry| sender.send(entry.clone()).unwrap()));
+        cpu.load_assembly("
+            set 0 5
+            str 101 0   ; handler for the timer
+            eni
+            hlt
+            hlt
+            set 1 9
+            hlt
+        ").unwrap();
+
+        cpu.run().unwrap();
+        let entries: Vec<TraceEntry> = receiver.try_iter().collect();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[3].pc, 3);
+        assert_eq!(entries[3].instruction.as_assembly(), "int 1i");
+        assert_eq!(entries[3].writes, vec![
+            Write::Memory(65535, 3),
+            Write::Memory(65534, INTERRUPTS_ENABLED),
+            Write::Memory(65533, 5),
+            Write::Memory(65532, 0),
+        ]);
+        assert_eq!((entries[4].pc, entries[4].writes.clone()), (5, vec![Write::Register(1, 9)]));
+    }
+
+    #[test]
+    fn step_back() {
+        let mut cpu = Cpu::builder().history_limit(100).build();
+        cpu.load_assembly("
+            set 0 3
+            loop: sub 0 0r 1
+            str 10 0
+            psh 0r
+            jpt loop 0r
+        ").unwrap();
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.history().len(), 13);
+        assert_eq!(cpu.state(), &State::Halting);
+
+        assert!(cpu.step_back());
+        assert_eq!((cpu.pc(), cpu.sp(), cpu.cycles()), (4, 65533, 12));
+        assert_eq!(cpu.state(), &State::Running);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.memory[65533], 0);
+        assert_eq!(cpu.sp(), 65534);
+
+        while cpu.step_back() {}
+        assert_eq!((cpu.pc(), cpu.sp(), cpu.cycles()), (0, 65536, 0));
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.memory[10], 0);
+        assert_eq!(cpu.memory[65535], 0);
+        assert_eq!(cpu.flags(), Flags::default());
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory[10], 0);
+        assert_eq!(cpu.memory[65534], 1);
+
+        let mut cpu = Cpu::builder().history_limit(2).build();
+        cpu.load_assembly("set 0 1\nset 0 2\nset 0 3").unwrap();
+        cpu.run().unwrap();
+        assert!(cpu.step_back() && cpu.step_back());
+        assert!(!cpu.step_back());
+        assert_eq!((cpu.pc(), cpu.registers[0]), (1, 1));
+
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("set 0 1").unwrap();
+        cpu.run().unwrap();
+        assert!(!cpu.step_back());
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let mut cpu = Cpu::builder().max_cycles(10).build();
+        cpu.load_assembly("set 0 1\nloop: add 1 1r 1\njpt loop 0r").unwrap();
+
+        assert_eq!(trap_of(cpu.run()), Trap::CycleLimit(10));
+        assert_eq!(cpu.registers[1], 5);
+    }
+
+    #[test]
+    fn input_output() {
+        let mut cpu = Cpu::with_io(BufferIo::new(vec![4, 5]));
+        cpu <<= [0x50, 0, 0, 0];
+        cpu <<= [0x50, 1, 0, 0];
+        cpu <<= [0x20, 2, r(0), r(1)];
+        cpu <<= [0x51, 2, 0, 0];
+
+        assert_eq!(cpu.io().output, vec![9]);
+
+        let result = cpu.execute(Instruction::from([0x50, 0, 0, 0]));
+        assert!(matches!(trap_of(result), Trap::IoError(_)));
+    }
+
+    #[test]
+    fn load_partial_instruction() {
+        let mut cpu = Cpu::new();
+        match cpu.load_bytes(&[0x03, 0, 1, 0, 0]) {
+            Err(CpuError::Object(ObjectError::PartialInstruction { length: 5 })) => (),
+            other => panic!("Expected a partial instruction error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_object() {
+        let mut object = Object::new(vec![
+            [0x00, 0, 0, 0],
+            [0x01, 0, 1, 0],
+            [0x00, 0, 0, 0],
+        ]);
+        object.entry = 1;
+        object.data = vec![4, 5];
+
+        let mut cpu = Cpu::new();
+        cpu.load_object(&object.to_bytes()).unwrap();
+        assert_eq!(cpu.pc, 1);
+        assert_eq!(&cpu.memory[..3], &[4, 5, 0]);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[0], 5);
+
+        let mut corrupted = object.to_bytes();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(cpu.load_object(&corrupted), Err(CpuError::Object(ObjectError::ChecksumMismatch { .. }))));
+
+        let mut cpu = Cpu::builder().code_base(16).build();
+        let result = cpu.load_object(&object.to_bytes());
+        assert!(matches!(result, Err(CpuError::Object(ObjectError::CodeBaseMismatch { assembled: 0, loaded: 16 }))));
+    }
+
+    #[test]
+    fn set_wide() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("
+            set 0 200
+            set 1 0x123456789a
+            set 2 'a'
+        ").unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 200);
+        assert_eq!(cpu.registers[1], 0x12_3456_789a);
+        assert_eq!(cpu.registers[2], 97);
+        assert_eq!(cpu.pc, 6);
+
+        cpu.load_instructions(vec![Instruction::from([0x07, 0, 0b1000_0001, 0])]).unwrap();
+        cpu.set_pc(0);
+        assert_eq!(trap_of(cpu.run()), Trap::PcOutOfBounds);
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let mut cpu = Cpu::new();
+        cpu.registers[0] = 7;
+
+        cpu <<= [0x05, r(0), 0, 0];
+        cpu <<= [0x05, 9, 0, 0];
+        assert_eq!(cpu.sp, cpu.memory.len() - 2);
+        assert_eq!(cpu.memory[cpu.sp], 9);
+
+        cpu <<= [0x06, 1, 0, 0];
+        cpu <<= [0x06, 2, 0, 0];
+        assert_eq!(cpu.registers[1], 9);
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.sp, cpu.memory.len());
+    }
+
+    #[test]
+    fn stack_traps() {
+        let mut cpu = Cpu::new();
+        let result = cpu.execute(Instruction::from([0x06, 0, 0, 0]));
+        assert_eq!(trap_of(result), Trap::StackUnderflow);
+
+        let result = cpu.execute(Instruction::from([0x13, 0, 0, 0]));
+        assert_eq!(trap_of(result), Trap::StackUnderflow);
+
+        for _ in 0..STACK_WORDS {
+            cpu <<= [0x05, 1, 0, 0];
+        }
+        let result = cpu.execute(Instruction::from([0x05, 1, 0, 0]));
+        assert_eq!(trap_of(result), Trap::StackOverflow);
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut cpu = Cpu::new();
+        cpu.load_instructions(vec![
+            Instruction::from([0x03, 0, 3, 0]),
+            Instruction::from([0x12, 2, 0, 1]),
+            Instruction::from([0x12, 1, 0, 1]),
+            Instruction::from([0x00, 0, 0, 0]),
+            Instruction::from([0x20, 0, r(0), r(0)]),
+            Instruction::from([0x13, 0, 0, 0]),
+        ]).unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 12);
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.sp, cpu.memory.len());
+    }
 }
@@ -0,0 +1,94 @@
+/// Counts of the instructions a program executed, for comparing how much
+/// work different programs do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Executions of each opcode, indexed by opcode.
+    opcodes: Vec<u64>,
+    /// Executions of the instruction at each address, indexed by address.
+    hits: Vec<u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            opcodes: vec![0; 256],
+            hits: Vec::new(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self { Default::default() }
+
+    /// Counts an execution of `opcode` at `address`.
+    pub fn record(&mut self, address: usize, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+        if address >= self.hits.len() {
+            self.hits.resize(address + 1, 0);
+        }
+        self.hits[address] += 1;
+    }
+
+    /// The number of instructions recorded.
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    pub fn opcode_count(&self, opcode: u8) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    /// Each opcode that ran with its count, most frequent first.
+    pub fn opcodes(&self) -> Vec<(u8, u64)> {
+        let mut counts: Vec<(u8, u64)> = self.opcodes.iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(opcode, count)| (opcode as u8, *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    pub fn hits(&self, address: usize) -> u64 {
+        self.hits.get(address).copied().unwrap_or(0)
+    }
+
+    /// Each address that ran with its hit count, most frequent first.
+    pub fn hottest(&self) -> Vec<(usize, u64)> {
+        let mut hits: Vec<(usize, u64)> = self.hits.iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(address, count)| (address, *count))
+            .collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hits
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let mut stats = Stats::new();
+        stats.record(0, 0x03);
+        stats.record(1, 0x20);
+        stats.record(1, 0x20);
+        stats.record(5, 0x00);
+
+        assert_eq!(stats.instructions(), 4);
+        assert_eq!(stats.opcode_count(0x20), 2);
+        assert_eq!(stats.opcodes(), vec![(0x20, 2), (0x00, 1), (0x03, 1)]);
+        assert_eq!(stats.hits(1), 2);
+        assert_eq!(stats.hits(100), 0);
+        assert_eq!(stats.hottest(), vec![(1, 2), (0, 1), (5, 1)]);
+
+        stats.clear();
+        assert_eq!(stats.instructions(), 0);
+    }
+}
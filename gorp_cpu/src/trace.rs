@@ -0,0 +1,233 @@
+use std::io::{self, Read};
+
+use crate::Word;
+use crate::instruction::Instruction;
+
+/// The first bytes of a trace file.
+pub const MAGIC: [u8; 4] = *b"GTRC";
+
+/// One executed instruction and what it wrote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub instruction: Instruction,
+    /// The evaluated dest, op1 and op2 parameters.
+    pub operands: [Word; 3],
+    pub writes: Vec<Write>,
+}
+
+/// A value written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Write {
+    Register(usize, Word),
+    /// A memory or device address.
+    Memory(Word, Word),
+}
+
+/// Receives each instruction the machine executes.
+pub trait Tracer {
+    fn trace(&mut self, entry: &TraceEntry);
+
+    /// Finishes writing any buffered entries.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&TraceEntry)> Tracer for F {
+    fn trace(&mut self, entry: &TraceEntry) {
+        self(entry)
+    }
+}
+
+/// Writes entries in the compact trace format.
+///
+/// After `MAGIC`, each entry is its pc, the 4 instruction bytes, the 3
+/// operands, the number of writes, then each write as a kind byte (0 for a
+/// register, 1 for memory), index and value. Numbers other than the
+/// instruction are LEB128 varints, so most take a byte or two.
+pub struct TraceWriter<W: io::Write> {
+    output: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> TraceWriter<W> {
+    pub fn new(mut output: W) -> io::Result<Self> {
+        output.write_all(&MAGIC)?;
+        Ok(Self { output, error: None })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: io::Write> Tracer for TraceWriter<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            self.error = entry.write_to(&mut self.output).err();
+        }
+    }
+
+    /// Flushes the output, returning the first error hit while tracing.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.output.flush()
+    }
+}
+
+impl TraceEntry {
+    pub fn write_to<W: io::Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, self.pc as u64);
+        bytes.extend_from_slice(&u32::from(self.instruction).to_be_bytes());
+        for operand in &self.operands {
+            write_varint(&mut bytes, *operand);
+        }
+        write_varint(&mut bytes, self.writes.len() as u64);
+        for write in &self.writes {
+            let (kind, index, value) = match *write {
+                Write::Register(index, value) => (0, index as u64, value),
+                Write::Memory(address, value) => (1, address, value),
+            };
+            bytes.push(kind);
+            write_varint(&mut bytes, index);
+            write_varint(&mut bytes, value);
+        }
+        output.write_all(&bytes)
+    }
+
+    /// Reads the next entry, or `None` at the end of the trace.
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Option<Self>> {
+        let pc = match read_varint(input) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            pc => pc? as usize,
+        };
+        let mut instruction = [0; 4];
+        input.read_exact(&mut instruction)?;
+        let operands = [read_varint(input)?, read_varint(input)?, read_varint(input)?];
+
+        let count = read_varint(input)?;
+        let mut writes = Vec::new();
+        for _ in 0..count {
+            let mut kind = [0];
+            input.read_exact(&mut kind)?;
+            let (index, value) = (read_varint(input)?, read_varint(input)?);
+            writes.push(match kind[0] {
+                0 => Write::Register(index as usize, value),
+                1 => Write::Memory(index, value),
+                kind => return Err(invalid(format!("unknown write kind {}", kind))),
+            });
+        }
+
+        Ok(Some(Self { pc, instruction: Instruction::from(instruction), operands, writes }))
+    }
+}
+
+/// Reads a whole trace file.
+pub fn read_trace<R: Read>(mut input: R) -> io::Result<Vec<TraceEntry>> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid(String::from("not a gorp trace file")));
+    }
+
+    let mut entries = Vec::new();
+    while let Some(entry) = TraceEntry::read_from(&mut input)? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7F) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid(String::from("varint too long")))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl std::fmt::Display for Write {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Write::Register(index, value) => write!(f, "r{} = {}", index, value),
+            Write::Memory(address, value) => write!(f, "[{}] = {}", address, value),
+        }
+    }
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [dest, op1, op2] = self.operands;
+        write!(f, "{:>6}  {:<20} ; {} {} {}", self.pc, self.instruction.as_assembly(), dest, op1, op2)?;
+        for write in &self.writes {
+            write!(f, ", {}", write)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let entries = vec![
+            TraceEntry {
+                pc: 3,
+                instruction: Instruction::from([0x03, 0x81, 0x85, 0]),
+                operands: [1, 5, 0],
+                writes: vec![Write::Register(1, 5)],
+            },
+            TraceEntry {
+                pc: 300,
+                instruction: Instruction::from([0x02, 0x80, 0x81, 0x82]),
+                operands: [0, 1, 2],
+                writes: vec![Write::Memory(2, Word::MAX)],
+            },
+        ];
+
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for entry in &entries {
+            writer.trace(entry);
+        }
+        writer.flush().unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 4 + 12 + 22);
+        assert_eq!(read_trace(&bytes[..]).unwrap(), entries);
+
+        assert!(read_trace(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_trace(&b"nope"[..]).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let entry = TraceEntry {
+            pc: 3,
+            instruction: Instruction::from([0x03, 0x81, 0x85, 0]),
+            operands: [1, 5, 0],
+            writes: vec![Write::Register(1, 5), Write::Memory(7, 2)],
+        };
+        assert_eq!(entry.to_string(), "     3  set 1i 5i            ; 1 5 0, r1 = 5, [7] = 2");
+    }
+}
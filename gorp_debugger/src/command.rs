@@ -0,0 +1,115 @@
+use gorp_cpu::Word;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Step(usize),
+    Next,
+    Continue,
+    StepBack(usize),
+    ReverseContinue,
+    Break(usize),
+    Delete(usize),
+    Watch(usize),
+    Unwatch(usize),
+    Info,
+    Registers,
+    Register(usize),
+    SetRegister(usize, Word),
+    Memory(usize, usize),
+    Poke(usize, Word),
+    Disassemble(usize),
+    Help,
+    Quit,
+}
+
+pub const HELP: &str = "\
+step [n]            (s)  execute n instructions (default 1)
+next                (n)  execute one instruction, stepping over calls
+continue            (c)  run until a breakpoint, watchpoint, halt or trap
+back [n]            (sb) undo n instructions (default 1)
+reverse-continue    (rc) run backwards to a breakpoint, watchpoint or the oldest history
+break <address>     (b)  set a breakpoint where pc is address
+delete <address>    (d)  remove a breakpoint
+watch <address>     (w)  stop when a memory cell changes
+unwatch <address>        remove a watchpoint
+info                (i)  list breakpoints and watchpoints
+reg [n] [value]     (r)  show all registers, show register n, or set it
+mem <address> [n]   (m)  show n memory cells (default 1)
+poke <address> <v>  (p)  set a memory cell
+dis [n]                  disassemble n instructions around pc (default 5)
+help                (h)  show this message
+quit                (q)  exit the debugger
+An empty line repeats the previous command.";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or_else(|| String::from("No command given"))?;
+        let args = words
+            .map(parse_number)
+            .collect::<Result<Vec<Word>, String>>()?;
+
+        let command = match (name, args.as_slice()) {
+            ("s", []) | ("step", []) => Command::Step(1),
+            ("s", [n]) | ("step", [n]) => Command::Step(*n as usize),
+            ("n", []) | ("next", []) => Command::Next,
+            ("c", []) | ("continue", []) => Command::Continue,
+            ("sb", []) | ("back", []) => Command::StepBack(1),
+            ("sb", [n]) | ("back", [n]) => Command::StepBack(*n as usize),
+            ("rc", []) | ("reverse-continue", []) => Command::ReverseContinue,
+            ("b", [address]) | ("break", [address]) => Command::Break(*address as usize),
+            ("d", [address]) | ("delete", [address]) => Command::Delete(*address as usize),
+            ("w", [address]) | ("watch", [address]) => Command::Watch(*address as usize),
+            ("unwatch", [address]) => Command::Unwatch(*address as usize),
+            ("i", []) | ("info", []) => Command::Info,
+            ("r", []) | ("reg", []) => Command::Registers,
+            ("r", [n]) | ("reg", [n]) => Command::Register(*n as usize),
+            ("r", [n, value]) | ("reg", [n, value]) => Command::SetRegister(*n as usize, *value),
+            ("m", [address]) | ("mem", [address]) => Command::Memory(*address as usize, 1),
+            ("m", [address, count]) | ("mem", [address, count]) => Command::Memory(*address as usize, *count as usize),
+            ("p", [address, value]) | ("poke", [address, value]) => Command::Poke(*address as usize, *value),
+            ("dis", []) => Command::Disassemble(5),
+            ("dis", [count]) => Command::Disassemble(*count as usize),
+            ("h", []) | ("help", []) => Command::Help,
+            ("q", []) | ("quit", []) => Command::Quit,
+            _ => return Err(format!("Invalid command: {}", line.trim())),
+        };
+
+        Ok(command)
+    }
+}
+
+fn parse_number(word: &str) -> Result<Word, String> {
+    let parsed = if let Some(hex) = word.strip_prefix("0x") {
+        Word::from_str_radix(hex, 16)
+    } else {
+        word.parse::<Word>()
+    };
+
+    parsed.map_err(|_| format!("Invalid number: {}", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("s"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("step 4"), Ok(Command::Step(4)));
+        assert_eq!(Command::parse("  b 12 "), Ok(Command::Break(12)));
+        assert_eq!(Command::parse("reg 3 0x10"), Ok(Command::SetRegister(3, 16)));
+        assert_eq!(Command::parse("mem 100"), Ok(Command::Memory(100, 1)));
+        assert_eq!(Command::parse("dis"), Ok(Command::Disassemble(5)));
+        assert_eq!(Command::parse("back 3"), Ok(Command::StepBack(3)));
+        assert_eq!(Command::parse("rc"), Ok(Command::ReverseContinue));
+    }
+
+    #[test]
+    fn reject_bad_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("taco").is_err());
+        assert!(Command::parse("break").is_err());
+        assert!(Command::parse("poke 1 x").is_err());
+    }
+}
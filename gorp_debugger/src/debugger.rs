@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use gorp_cpu::{Cpu, StepResult, Word};
+use gorp_cpu::instruction::Instruction;
+
+use crate::command::{Command, HELP};
+
+pub struct Debugger {
+    cpu: Cpu,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, Word>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            finished: false,
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Runs a command and returns the text to show the user.
+    pub fn execute(&mut self, command: &Command) -> String {
+        let mut output = String::new();
+
+        match *command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if self.step(&mut output) {
+                        break;
+                    }
+                }
+                self.disassemble(&mut output, 0);
+            },
+            Command::Next => {
+                let call = self.cpu.instruction_at(self.cpu.pc())
+                    .is_some_and(|instruction| instruction.mnemonic() == Some("cal"));
+                let (return_pc, sp) = (self.cpu.pc() + 1, self.cpu.sp());
+                let mut stop = self.step(&mut output);
+                // Run the whole subroutine when stepping over a call
+                while call && !stop && (self.cpu.pc() != return_pc || self.cpu.sp() != sp) {
+                    stop = self.step(&mut output);
+                    if !stop && self.breakpoints.contains(&self.cpu.pc()) {
+                        let _ = writeln!(output, "Breakpoint at {}", self.cpu.pc());
+                        break;
+                    }
+                }
+                self.disassemble(&mut output, 0);
+            },
+            Command::Continue => {
+                while !self.step(&mut output) {
+                    if self.breakpoints.contains(&self.cpu.pc()) {
+                        let _ = writeln!(output, "Breakpoint at {}", self.cpu.pc());
+                        break;
+                    }
+                }
+                self.disassemble(&mut output, 0);
+            },
+            Command::StepBack(count) => {
+                for _ in 0..count {
+                    if self.step_back(&mut output) {
+                        break;
+                    }
+                }
+                self.disassemble(&mut output, 0);
+            },
+            Command::ReverseContinue => {
+                while !self.step_back(&mut output) {
+                    if self.breakpoints.contains(&self.cpu.pc()) {
+                        let _ = writeln!(output, "Breakpoint at {}", self.cpu.pc());
+                        break;
+                    }
+                }
+                self.disassemble(&mut output, 0);
+            },
+            Command::Break(address) => {
+                if self.listing(address).last().is_some_and(|(index, _, data)| *index == address && *data) {
+                    let _ = writeln!(output, "Address {} holds setw data, not an instruction", address);
+                } else {
+                    self.breakpoints.insert(address);
+                    let _ = writeln!(output, "Breakpoint set at {}", address);
+                }
+            },
+            Command::Delete(address) => {
+                if self.breakpoints.remove(&address) {
+                    let _ = writeln!(output, "Breakpoint removed at {}", address);
+                } else {
+                    let _ = writeln!(output, "No breakpoint at {}", address);
+                }
+            },
+            Command::Watch(address) => match self.cpu.memory().get(address) {
+                Some(value) => {
+                    self.watchpoints.insert(address, *value);
+                    let _ = writeln!(output, "Watching memory[{}] = {}", address, value);
+                },
+                None => {
+                    let _ = writeln!(output, "Address {} is out of range", address);
+                },
+            },
+            Command::Unwatch(address) => {
+                if self.watchpoints.remove(&address).is_some() {
+                    let _ = writeln!(output, "Watchpoint removed at {}", address);
+                } else {
+                    let _ = writeln!(output, "No watchpoint at {}", address);
+                }
+            },
+            Command::Info => {
+                let _ = writeln!(output, "Breakpoints: {:?}", self.breakpoints);
+                let _ = writeln!(output, "Watchpoints: {:?}", self.watchpoints.keys().collect::<Vec<_>>());
+                let history = self.cpu.history();
+                let _ = writeln!(output, "History: {} of {} steps", history.len(), history.limit());
+            },
+            Command::Registers => {
+                let _ = writeln!(
+                    output,
+                    "pc: {}  sp: {}  flags: {}  state: {:?}",
+                    self.cpu.pc(),
+                    self.cpu.sp(),
+                    self.cpu.flags(),
+                    self.cpu.state(),
+                );
+                for (index, value) in self.cpu.registers().iter().enumerate() {
+                    let _ = writeln!(output, "r{:<2} = {}", index, value);
+                }
+            },
+            Command::Register(index) => match self.cpu.registers().get(index) {
+                Some(value) => {
+                    let _ = writeln!(output, "r{} = {}", index, value);
+                },
+                None => {
+                    let _ = writeln!(output, "Register {} does not exist", index);
+                },
+            },
+            Command::SetRegister(index, value) => match self.cpu.registers_mut().get_mut(index) {
+                Some(register) => {
+                    *register = value;
+                    let _ = writeln!(output, "r{} = {}", index, value);
+                },
+                None => {
+                    let _ = writeln!(output, "Register {} does not exist", index);
+                },
+            },
+            Command::Memory(address, count) => {
+                let end = usize::min(address.saturating_add(count), self.cpu.memory().len());
+                if address >= end {
+                    let _ = writeln!(output, "Address {} is out of range", address);
+                }
+                for index in address..end {
+                    let _ = writeln!(output, "memory[{}] = {}", index, self.cpu.memory()[index]);
+                }
+            },
+            Command::Poke(address, value) => match self.cpu.memory_mut().get_mut(address) {
+                Some(cell) => {
+                    *cell = value;
+                    if let Some(watched) = self.watchpoints.get_mut(&address) {
+                        *watched = value;
+                    }
+                    let _ = writeln!(output, "memory[{}] = {}", address, value);
+                },
+                None => {
+                    let _ = writeln!(output, "Address {} is out of range", address);
+                },
+            },
+            Command::Disassemble(count) => self.disassemble(&mut output, count / 2),
+            Command::Help => {
+                let _ = writeln!(output, "{}", HELP);
+            },
+            Command::Quit => (),
+        }
+
+        output
+    }
+
+    /// Executes one instruction, returning `true` if execution should stop.
+    fn step(&mut self, output: &mut String) -> bool {
+        if self.finished {
+            let _ = writeln!(output, "The program is not running");
+            return true;
+        }
+
+        let mut stop = match self.cpu.step() {
+            StepResult::Running => false,
+            StepResult::Halted => {
+                let _ = writeln!(output, "Program halted");
+                self.finished = true;
+                true
+            },
+            StepResult::Trapped(error) => {
+                let _ = writeln!(output, "Trap: {}", error);
+                self.finished = true;
+                true
+            },
+        };
+
+        stop |= self.check_watchpoints(output);
+        stop
+    }
+
+    /// Undoes one instruction, returning `true` if reversing should stop.
+    fn step_back(&mut self, output: &mut String) -> bool {
+        if !self.cpu.step_back() {
+            let _ = writeln!(output, "No more history");
+            return true;
+        }
+
+        self.finished = false;
+        self.check_watchpoints(output)
+    }
+
+    /// Reports watched memory cells that changed, returning `true` if any did.
+    fn check_watchpoints(&mut self, output: &mut String) -> bool {
+        let mut changed = false;
+        for (address, watched) in self.watchpoints.iter_mut() {
+            let value = self.cpu.memory()[*address];
+            if value != *watched {
+                let _ = writeln!(output, "Watchpoint memory[{}]: {} -> {}", address, watched, value);
+                *watched = value;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Each instruction from the start of the program through `end`, and
+    /// whether it's really data following a `setw`.
+    fn listing(&self, end: usize) -> Vec<(usize, Instruction, bool)> {
+        let start = self.cpu.config().code_base.unwrap_or(0);
+        let mut data_words = 0;
+        (start..=end)
+            .map_while(|index| self.cpu.instruction_at(index).map(|instruction| (index, instruction)))
+            .map(|(index, instruction)| {
+                let data = data_words > 0;
+                data_words = if data { data_words - 1 } else { instruction.extension_words() };
+                (index, instruction, data)
+            })
+            .collect()
+    }
+
+    fn disassemble(&self, output: &mut String, context: usize) {
+        let pc = self.cpu.pc();
+        let start = pc.saturating_sub(context);
+
+        for (index, instruction, data) in self.listing(pc + context).into_iter().filter(|(index, ..)| *index >= start) {
+            let marker = if index == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&index) { "*" } else { " " };
+            let assembly = if data {
+                format!(".word 0x{:08x}", u32::from(instruction))
+            } else {
+                instruction.as_assembly()
+            };
+            let _ = writeln!(output, "{}{} {:>4}: {}", marker, breakpoint, index, assembly);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger() -> Debugger {
+        let mut cpu = Cpu::builder().history_limit(100).build();
+        cpu.load_assembly("
+            set 0 1
+            set 1 1
+            add 1 0r 1r
+            str 5 1
+            let 3 1r 8
+            jpt 4 3r 0
+            hlt
+        ").unwrap();
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn breakpoints_stop_continue() {
+        let mut debugger = debugger();
+        debugger.execute(&Command::Break(4));
+
+        let output = debugger.execute(&Command::Continue);
+        assert!(output.contains("Breakpoint at 4"));
+        assert_eq!(debugger.cpu().pc(), 4);
+        assert_eq!(debugger.cpu().registers()[1], 2);
+
+        debugger.execute(&Command::Continue);
+        assert_eq!(debugger.cpu().registers()[1], 3);
+
+        debugger.execute(&Command::Delete(4));
+        let output = debugger.execute(&Command::Continue);
+        assert!(output.contains("Program halted"));
+        assert_eq!(debugger.cpu().registers()[1], 8);
+    }
+
+    #[test]
+    fn breakpoints_in_memory() {
+        let mut cpu = Cpu::builder().code_base(16).build();
+        cpu.load_assembly("set 0 1\nset 1 2\nset 2 3").unwrap();
+        let mut debugger = Debugger::new(cpu);
+        debugger.execute(&Command::Break(18));
+
+        let output = debugger.execute(&Command::Continue);
+        assert!(output.contains("Breakpoint at 18"));
+        assert!(output.contains("=>*   18: set 2i 3i"));
+        assert_eq!(debugger.cpu().registers()[1], 2);
+    }
+
+    #[test]
+    fn watchpoints_stop_continue() {
+        let mut debugger = debugger();
+        debugger.execute(&Command::Watch(5));
+
+        let output = debugger.execute(&Command::Continue);
+        assert!(output.contains("Watchpoint memory[5]: 0 -> 2"));
+        assert_eq!(debugger.cpu().pc(), 4);
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("
+            set 0 3
+            cal double
+            hlt
+            double: add 0 0r 0r
+            ret
+        ").unwrap();
+        let mut debugger = Debugger::new(cpu);
+
+        debugger.execute(&Command::Next);
+        debugger.execute(&Command::Next);
+        assert_eq!(debugger.cpu().pc(), 2);
+        assert_eq!(debugger.cpu().registers()[0], 6);
+
+        debugger.execute(&Command::Step(1));
+        assert!(debugger.execute(&Command::Next).contains("not running"));
+    }
+
+    #[test]
+    fn step_back_and_reverse_continue() {
+        let mut debugger = debugger();
+        debugger.execute(&Command::Continue);
+        assert_eq!(debugger.cpu().registers()[1], 8);
+
+        debugger.execute(&Command::StepBack(3));
+        assert_eq!(debugger.cpu().pc(), 4);
+        assert_eq!(debugger.cpu().registers()[3], 1);
+
+        debugger.execute(&Command::Watch(5));
+        let output = debugger.execute(&Command::ReverseContinue);
+        assert!(output.contains("Watchpoint memory[5]: 8 -> 7"));
+        assert_eq!(debugger.cpu().pc(), 3);
+
+        debugger.execute(&Command::Unwatch(5));
+        debugger.execute(&Command::Break(2));
+        let output = debugger.execute(&Command::ReverseContinue);
+        assert!(output.contains("Breakpoint at 2"));
+        assert_eq!(debugger.cpu().registers()[1], 7);
+
+        debugger.execute(&Command::Delete(2));
+        let output = debugger.execute(&Command::ReverseContinue);
+        assert!(output.contains("No more history"));
+        assert_eq!(debugger.cpu().pc(), 0);
+
+        debugger.execute(&Command::Continue);
+        assert_eq!(debugger.cpu().registers()[1], 8);
+    }
+
+    #[test]
+    fn wide_constants_are_data() {
+        let mut cpu = Cpu::new();
+        cpu.load_assembly("set 0 300\nhlt").unwrap();
+        let mut debugger = Debugger::new(cpu);
+
+        let output = debugger.execute(&Command::Disassemble(4));
+        assert!(output.contains("   1: .word 0x0000012c"));
+        assert!(output.contains("   2: hlt"));
+
+        let output = debugger.execute(&Command::Break(1));
+        assert!(output.contains("setw data"));
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn modify_state() {
+        let mut debugger = debugger();
+        debugger.execute(&Command::SetRegister(2, 7));
+        debugger.execute(&Command::Poke(10, 11));
+
+        assert_eq!(debugger.cpu().registers()[2], 7);
+        assert_eq!(debugger.cpu().memory()[10], 11);
+    }
+}
@@ -0,0 +1,65 @@
+mod command;
+mod debugger;
+
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use gorp_cpu::Cpu;
+
+use crate::command::Command;
+use crate::debugger::Debugger;
+
+fn main() {
+    let options = Options::from_args();
+
+    let mut cpu = Cpu::builder().history_limit(options.history).build();
+    if let Err(error) = cpu.load_assembly_file(&options.path) {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+
+    let mut debugger = Debugger::new(cpu);
+    println!("Debugging {:?} ({} instructions)", &options.path, debugger.cpu().rom().len());
+    println!("Type \"help\" for a list of commands");
+
+    let mut last_command = None;
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("(gorp) ");
+        io::stdout().flush().expect("Error writing to stdout");
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+
+        let command = if line.trim().is_empty() {
+            match &last_command {
+                Some(command) => Ok(Command::clone(command)),
+                None => continue,
+            }
+        } else {
+            Command::parse(&line)
+        };
+
+        match command {
+            Ok(Command::Quit) => break,
+            Ok(command) => {
+                print!("{}", debugger.execute(&command));
+                last_command = Some(command);
+            },
+            Err(message) => println!("{}", message),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct Options {
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+    /// How many instructions back and reverse-continue can undo
+    #[structopt(long, default_value = "100000")]
+    pub history: usize,
+}
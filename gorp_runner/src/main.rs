@@ -1,33 +1,356 @@
-use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use gorp_asm::object::{self, Object, Profile};
 use gorp_cpu::Cpu;
+use gorp_cpu::bus::Device;
+use gorp_cpu::config::CpuConfig;
+use gorp_cpu::devices::{BlockStorage, Console, Framebuffer, Random, Timer};
+use gorp_cpu::error::CpuError;
+use gorp_cpu::instruction::Instruction;
+use gorp_cpu::trace::{self, TraceEntry, TraceWriter};
 
 fn main() {
-    let options = Options::from_args();
-    
-    let run_message = format!("Running: {:?}", &options.path);
-    let terminated_message = format!("{:?} terminated successfully", &options.path);
+    let result = match Command::from_args() {
+        Command::Asm { input, output, machine } => assemble(&input, output, &machine),
+        Command::Disasm { input } => disassemble(&input),
+        Command::Run { path, machine, devices, stats, trace } => run(&path, &machine, &devices, stats, trace.as_deref()),
+        Command::Trace(TraceCommand::Replay { trace }) => replay_trace(&trace),
+        Command::Trace(TraceCommand::Diff { first, second }) => diff_traces(&first, &second),
+    };
+
+    if let Err(error) = result {
+        eprintln!();
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "gorp", about = "Assemble, inspect and run gorp programs")]
+enum Command {
+    /// Assemble a .gas source file into a .gorp object file
+    Asm {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        /// Defaults to the input path with a .gorp extension
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// The machine to store in the object header
+        #[structopt(flatten)]
+        machine: MachineArgs,
+    },
+    /// Print the assembly for a .gorp object file
+    Disasm {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Run a .gas source file or a .gorp object file
+    Run {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Overrides the machine stored in an object file
+        #[structopt(flatten)]
+        machine: MachineArgs,
+        /// Maps a device into memory, written kind@address. Kinds are console,
+        /// timer[:period[,interrupt]], random[:seed], framebuffer:WIDTHxHEIGHT and storage:PATH
+        #[structopt(long = "device", number_of_values = 1, parse(try_from_str = parse_device))]
+        devices: Vec<DeviceSpec>,
+        /// Print how many instructions ran, by opcode and by address
+        #[structopt(long)]
+        stats: bool,
+        /// Record every instruction executed to a trace file
+        #[structopt(long, parse(from_os_str))]
+        trace: Option<PathBuf>,
+    },
+    /// Inspect trace files recorded with run --trace
+    Trace(TraceCommand),
+}
+
+#[derive(StructOpt)]
+enum TraceCommand {
+    /// Print each instruction in a trace with what it wrote
+    Replay {
+        #[structopt(parse(from_os_str))]
+        trace: PathBuf,
+    },
+    /// Find where two traces first differ
+    Diff {
+        #[structopt(parse(from_os_str))]
+        first: PathBuf,
+        #[structopt(parse(from_os_str))]
+        second: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+struct MachineArgs {
+    /// Start from a named machine: tiny, default or large
+    #[structopt(long)]
+    profile: Option<String>,
+    #[structopt(long)]
+    memory_words: Option<u32>,
+    #[structopt(long)]
+    registers: Option<u16>,
+    #[structopt(long)]
+    stack_words: Option<u32>,
+    /// The longest ROM the machine will load, in instructions
+    #[structopt(long)]
+    max_rom: Option<u32>,
+    /// Stop with an error after this many instructions
+    #[structopt(long)]
+    max_cycles: Option<u64>,
+    /// Run in von Neumann mode, loading code into memory at this address
+    #[structopt(long)]
+    code_base: Option<u32>,
+    /// Where the interrupt vector table starts in memory
+    #[structopt(long)]
+    vector_table: Option<u32>,
+}
+
+impl MachineArgs {
+    /// The machine to run on, starting from an object file's profile.
+    fn config(&self, object: &Profile) -> Result<CpuConfig, String> {
+        let mut config = CpuConfig::default().with_profile(object).with_profile(&self.profile()?);
+        // The profile can't hold a vector table at 0, so apply it here too
+        if let Some(address) = self.vector_table {
+            config = config.vector_table(address as usize);
+        }
+        Ok(config)
+    }
+
+    /// The selected profile with any individual settings applied on top.
+    fn profile(&self) -> Result<Profile, String> {
+        let mut profile = match self.profile.as_deref() {
+            None | Some("default") => Profile::default(),
+            Some("tiny") => CpuConfig::tiny().profile(),
+            Some("large") => CpuConfig::large().profile(),
+            Some(name) => return Err(format!("Unknown profile {:?}, expected tiny, default or large", name)),
+        };
+
+        profile.registers = self.registers.unwrap_or(profile.registers);
+        profile.memory_words = self.memory_words.unwrap_or(profile.memory_words);
+        profile.stack_words = self.stack_words.unwrap_or(profile.stack_words);
+        profile.max_rom = self.max_rom.unwrap_or(profile.max_rom);
+        profile.max_cycles = self.max_cycles.unwrap_or(profile.max_cycles);
+        profile.code_base = self.code_base.or(profile.code_base);
+        profile.vector_table = self.vector_table.unwrap_or(profile.vector_table);
+        Ok(profile)
+    }
+}
+
+/// A device to attach when running, parsed from `kind[:argument]@address`.
+struct DeviceSpec {
+    kind: String,
+    argument: Option<String>,
+    address: usize,
+}
+
+fn parse_device(spec: &str) -> Result<DeviceSpec, String> {
+    let (device, address) = spec.rsplit_once('@')
+        .ok_or_else(|| format!("Expected kind@address, found {:?}", spec))?;
+    let address = gorp_asm::parse_literal(address)
+        .and_then(|address| usize::try_from(address).ok())
+        .ok_or_else(|| format!("Invalid device address {:?}", address))?;
+    let (kind, argument) = match device.split_once(':') {
+        Some((kind, argument)) => (kind, Some(argument.to_string())),
+        None => (device, None),
+    };
+
+    Ok(DeviceSpec { kind: kind.to_string(), argument, address })
+}
+
+impl DeviceSpec {
+    fn build(&self) -> Result<Box<dyn Device>, String> {
+        let argument = self.argument.as_deref();
+        Ok(match (self.kind.as_str(), argument) {
+            ("console", None) => Box::new(Console::new()),
+            ("timer", None) => Box::new(Timer::new()),
+            ("timer", Some(argument)) => {
+                let (period, interrupt) = argument.split_once(',').unwrap_or((argument, "0"));
+                let period = gorp_asm::parse_literal(period).ok_or_else(|| format!("Invalid timer period {:?}", period))?;
+                let interrupt = interrupt.parse().map_err(|_| format!("Invalid timer interrupt {:?}", interrupt))?;
+                Box::new(Timer::every(period, interrupt))
+            },
+            ("random", None) => {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+                Box::new(Random::new(now.as_nanos() as u64))
+            },
+            ("random", Some(seed)) => {
+                let seed = gorp_asm::parse_literal(seed).ok_or_else(|| format!("Invalid random seed {:?}", seed))?;
+                Box::new(Random::new(seed))
+            },
+            ("framebuffer", Some(size)) => {
+                let (width, height) = size.split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .ok_or_else(|| format!("Expected a framebuffer size like 40x12, found {:?}", size))?;
+                Box::new(Framebuffer::new(width, height))
+            },
+            ("storage", Some(path)) => Box::new(BlockStorage::open(path).map_err(|error| format!("{}: {}", path, error))?),
+            ("framebuffer", None) => return Err(String::from("The framebuffer device needs a size, like framebuffer:40x12")),
+            ("storage", None) => return Err(String::from("The storage device needs a path, like storage:disk.img")),
+            (kind, _) => return Err(format!("Unknown device {:?}, expected console, timer, random, framebuffer or storage", kind)),
+        })
+    }
+}
+
+fn assemble(input: &Path, output: Option<PathBuf>, machine: &MachineArgs) -> Result<(), String> {
+    let source = std::fs::read_to_string(input).map_err(|error| error.to_string())?;
+    let profile = machine.profile()?;
+    let code_base = profile.code_base.unwrap_or(0) as usize;
+    let mut object = gorp_asm::assemble_object_at(&source, code_base).map_err(|errors| CpuError::Asm(errors).to_string())?;
+    object.profile = profile;
+    let bytes = object.to_bytes();
+
+    let output = output.unwrap_or_else(|| input.with_extension("gorp"));
+    std::fs::write(&output, &bytes).map_err(|error| error.to_string())?;
+    println!("Wrote {} bytes to {:?}", bytes.len(), output);
+    Ok(())
+}
+
+fn disassemble(input: &Path) -> Result<(), String> {
+    let bytes = std::fs::read(input).map_err(|error| error.to_string())?;
+    let object = Object::from_bytes(&bytes).map_err(|error| error.to_string())?;
+
+    println!("; entry point: {}", object.entry);
+    if object.profile != Profile::default() {
+        println!("; profile: {:?}", object.profile);
+    }
+    for (address, word) in object.data.iter().enumerate() {
+        println!("; data[{}] = {}", address, word);
+    }
+
+    let mut data_words = 0;
+    for (index, bytes) in object.rom.iter().enumerate() {
+        for (label, _) in object.symbols.iter().filter(|(_, address)| *address as usize == index) {
+            println!("{}:", label);
+        }
+        let instruction = Instruction::from(*bytes);
+        let assembly = if data_words > 0 {
+            data_words -= 1;
+            format!(".word 0x{:08x}", u32::from(instruction))
+        } else {
+            data_words = instruction.extension_words();
+            instruction.as_assembly()
+        };
+        println!("    {:<24} ; {}", assembly, index);
+    }
+    Ok(())
+}
+
+fn run(path: &Path, machine: &MachineArgs, devices: &[DeviceSpec], stats: bool, trace: Option<&Path>) -> Result<(), String> {
+    let mut cpu = load_program(path, machine)?;
+    for spec in devices {
+        cpu.attach(spec.address, spec.build()?).map_err(|error| error.to_string())?;
+    }
+    if let Some(trace) = trace {
+        let file = std::fs::File::create(trace).map_err(|error| format!("{:?}: {}", trace, error))?;
+        let writer = TraceWriter::new(BufWriter::new(file)).map_err(|error| error.to_string())?;
+        cpu.set_tracer(Box::new(writer));
+    }
+
+    let run_message = format!("Running: {:?}", path);
+    let terminated_message = format!("{:?} terminated successfully", path);
     let padding_len = usize::max(run_message.len(), terminated_message.len());
 
     println!("{0:-<1$}", "", padding_len + 4);
     println!("| {0:^1$} |", &run_message, padding_len);
     println!("{0:-<1$}", "", padding_len + 4);
-    println!("");
-    
-    
-    let mut cpu = Cpu::new();
-    cpu.load_assembly_file(&options.path);
-    cpu.run();
-
-    
-    println!("");
+    println!();
+
+
+    let result = cpu.run();
+    if stats {
+        print_stats(&cpu);
+    }
+    if let Some(mut tracer) = cpu.take_tracer() {
+        tracer.flush().map_err(|error| format!("Writing trace: {}", error))?;
+    }
+    result.map_err(|error| error.to_string())?;
+
+
+    println!();
     println!("{0:-<1$}", "", padding_len + 4);
     println!("| {0:^1$} |", &terminated_message, padding_len);
     println!("{0:-<1$}", "", padding_len + 4);
+    Ok(())
 }
 
-#[derive(StructOpt)]
-struct Options {
-    #[structopt(parse(from_os_str))]
-    pub path: PathBuf,
+fn read_trace_file(path: &Path) -> Result<Vec<TraceEntry>, String> {
+    let file = std::fs::File::open(path).map_err(|error| format!("{:?}: {}", path, error))?;
+    trace::read_trace(BufReader::new(file)).map_err(|error| format!("{:?}: {}", path, error))
+}
+
+fn replay_trace(path: &Path) -> Result<(), String> {
+    for entry in read_trace_file(path)? {
+        println!("{}", entry);
+    }
+    Ok(())
+}
+
+/// The number of matching entries shown before the first difference.
+const DIFF_CONTEXT: usize = 3;
+
+fn diff_traces(first: &Path, second: &Path) -> Result<(), String> {
+    let (first_entries, second_entries) = (read_trace_file(first)?, read_trace_file(second)?);
+    let common = first_entries.iter().zip(&second_entries).take_while(|(a, b)| a == b).count();
+    if common == first_entries.len() && common == second_entries.len() {
+        println!("Traces match for all {} instructions", common);
+        return Ok(());
+    }
+
+    for entry in &first_entries[common.saturating_sub(DIFF_CONTEXT)..common] {
+        println!("  {}", entry);
+    }
+    for (marker, entry) in [("-", first_entries.get(common)), ("+", second_entries.get(common))] {
+        match entry {
+            Some(entry) => println!("{} {}", marker, entry),
+            None => println!("{} (end of trace)", marker),
+        }
+    }
+    Err(format!("Traces differ at instruction {}", common + 1))
+}
+
+/// The number of addresses listed in the `--stats` report.
+const HOTTEST_ADDRESSES: usize = 10;
+
+fn print_stats(cpu: &Cpu) {
+    let stats = cpu.stats();
+    let total = stats.instructions();
+    let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+
+    println!();
+    println!("{} instructions executed", total);
+    println!();
+    println!("  {:<8} {:>12} {:>7}", "opcode", "count", "%");
+    for (opcode, count) in stats.opcodes() {
+        let name = Instruction::from([opcode, 0, 0, 0]).mnemonic().map_or_else(|| format!("0x{:02x}", opcode), String::from);
+        println!("  {:<8} {:>12} {:>6.1}%", name, count, percent(count));
+    }
+
+    println!();
+    println!("  {:<8} {:>12} {:>7}  instruction", "address", "hits", "%");
+    for (address, hits) in stats.hottest().into_iter().take(HOTTEST_ADDRESSES) {
+        let assembly = cpu.instruction_at(address).map_or_else(String::new, |instruction| instruction.as_assembly());
+        println!("  {:<8} {:>12} {:>6.1}%  {}", address, hits, percent(hits), assembly);
+    }
+}
+
+/// Loads an object file if `path` has the object magic number, otherwise assembly source.
+///
+/// The machine is built from the object's profile, if it has one, overridden by `machine`.
+fn load_program(path: &Path, machine: &MachineArgs) -> Result<Cpu, String> {
+    let bytes = std::fs::read(path).map_err(|error| error.to_string())?;
+    if object::is_object(&bytes) {
+        let object = Object::from_bytes(&bytes).map_err(|error| error.to_string())?;
+        let mut cpu = machine.config(&object.profile)?.build();
+        cpu.load_object(&bytes).map_err(|error| error.to_string())?;
+        Ok(cpu)
+    } else {
+        let source = String::from_utf8(bytes).map_err(|error| error.to_string())?;
+        let mut cpu = machine.config(&Profile::default())?.build();
+        cpu.load_assembly(&source).map_err(|error| error.to_string())?;
+        Ok(cpu)
+    }
 }
@@ -0,0 +1,60 @@
+use std::convert::TryFrom;
+
+use gorp_asm::parse_instruction;
+use gorp_cpu::instruction::Instruction;
+
+fn assert_round_trip(word: u32) {
+    let instruction = Instruction::from(word);
+    let assembly = instruction.as_assembly();
+    let reassembled = parse_instruction(&assembly)
+        .unwrap_or_else(|error| panic!("{:08x} disassembled to invalid assembly:\n{}", word, error));
+
+    assert_eq!(
+        Instruction::from(reassembled),
+        instruction,
+        "{:08x} disassembled to `{}`",
+        word,
+        assembly,
+    );
+}
+
+#[test]
+fn every_opcode_and_operand_byte() {
+    for opcode in 0..=0xFFu32 {
+        for byte in 0..=0xFFu32 {
+            assert_round_trip(opcode << 24 | byte << 16);
+            assert_round_trip(opcode << 24 | byte << 8);
+            assert_round_trip(opcode << 24 | byte);
+        }
+    }
+}
+
+#[test]
+fn sampled_encodings() {
+    // xorshift32, so failures are reproducible
+    let mut state = 0x2545_F491u32;
+    for _ in 0..200_000 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        assert_round_trip(state);
+    }
+}
+
+#[test]
+fn program_disassembly_reassembles() {
+    let source = "
+        set 0 1
+        loop: add 1 0r 1r
+        let 3 1r 8
+        jpt loop 3r
+        hlt
+    ";
+    let rom = gorp_asm::parse_program(source).unwrap();
+    let disassembly: Vec<String> = rom.iter()
+        .map(|bytes| Instruction::from(*bytes).as_assembly())
+        .collect();
+
+    assert_eq!(gorp_asm::parse_program(&disassembly.join("\n")).unwrap(), rom);
+    assert_eq!(Instruction::try_from(disassembly[1].as_str()).unwrap(), Instruction::from(rom[1]));
+}
@@ -14,19 +14,37 @@ fn looping_addition_program() {
     hlt
     set 2 9
     ";
-    cpu.load_assembly(program);
+    cpu.load_assembly(program).unwrap();
+    looping_addition_test(cpu);
+}
+
+#[test]
+fn looping_addition_with_labels() {
+    let mut cpu = Cpu::new();
+    let program = "
+    set 0 1
+    set 1 1
+    loop: add 1 0r 1r
+    let 3 1r 8
+    jpt loop 3r
+    jpf end 2r
+    hlt
+    hlt
+    end: set 2 9
+    ";
+    cpu.load_assembly(program).unwrap();
     looping_addition_test(cpu);
 }
 
 #[test]
 fn looping_addition_from_file() {
     let mut cpu = Cpu::new();
-    cpu.load_assembly_file("./tests/resources/simple_program.gas");
+    cpu.load_assembly_file("./tests/resources/simple_program.gas").unwrap();
     looping_addition_test(cpu);
 }
 
 fn looping_addition_test(mut cpu: Cpu) {
-    cpu.run();
+    cpu.run().unwrap();
 
     assert_eq!(cpu.registers()[0], 1);
     assert_eq!(cpu.registers()[1], 8);